@@ -0,0 +1,660 @@
+use anyhow::{bail, Context, Result};
+use audiopus::{coder::Encoder, Application, Bitrate, Channels, SampleRate};
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::{runtime::Builder, sync::mpsc};
+use webrtc::api::media_engine::MIME_TYPE_OPUS;
+use webrtc::media::Sample;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+use crate::{consent::sharing_allowed, stats::FrameCounter, RTC_AUDIO_TRACK};
+
+/// Opus always runs its RTP clock at 48 kHz, whatever the input rate.
+pub const SAMPLE_RATE: u32 = 48000;
+/// Channels of the audio track; mono sources are sent on both.
+pub const CHANNELS: u16 = 2;
+pub const FRAME_DURATION: Duration = Duration::from_millis(20);
+/// Samples per channel in one [`FRAME_DURATION`].
+pub const FRAME_SAMPLES: usize = (SAMPLE_RATE as usize / 1000) * 20;
+/// Opus bitrate of the audio track, in bits per second.
+pub const BITRATE: i32 = 64_000;
+/// Upper bound on an encoded frame, so every packet fits the MTU.
+const MAX_PACKET_SIZE: usize = 1200;
+/// Longest the audio is held back to line up with the video.
+pub const MAX_SYNC_DELAY: Duration = Duration::from_millis(500);
+
+/// Only one audio capture may write to [`RTC_AUDIO_TRACK`] at a time.
+static AUDIO_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Codec of the audio track [`crate::sdp::init_sdp_with`] adds.
+pub fn opus_capability() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_OPUS.to_owned(),
+        clock_rate: SAMPLE_RATE,
+        channels: CHANNELS,
+        sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+        rtcp_feedback: vec![],
+    }
+}
+
+/// Interleaved 16-bit PCM at [`SAMPLE_RATE`], as produced by an [`AudioSource`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioFrame {
+    pub samples: Vec<i16>,
+    pub channels: u16,
+    /// Monotonic time at which the frame was captured.
+    pub captured_at: Instant,
+}
+
+impl AudioFrame {
+    /// Create a frame captured now.
+    pub fn new(samples: Vec<i16>, channels: u16) -> Self {
+        AudioFrame {
+            samples,
+            channels,
+            captured_at: Instant::now(),
+        }
+    }
+
+    /// Samples per channel.
+    pub fn sample_count(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+}
+
+/// Something the audio capture can pull frames from, usually [`FRAME_SAMPLES`] at a time.
+///
+/// Sources are opened on the audio thread, so they do not need to be `Send`. Sources that
+/// return immediately are paced to real time by the capture.
+pub trait AudioSource {
+    /// Block until the next frame is available. `Ok(None)` ends the stream.
+    fn next_frame(&mut self) -> Result<Option<AudioFrame>>;
+}
+
+impl<S: AudioSource + ?Sized> AudioSource for Box<S> {
+    fn next_frame(&mut self) -> Result<Option<AudioFrame>> {
+        (**self).next_frame()
+    }
+}
+
+/// Synthetic source playing a sine tone.
+pub struct SineSource {
+    frequency: f64,
+    channels: u16,
+    position: u64,
+    limit: Option<u64>,
+}
+
+impl SineSource {
+    pub fn new(frequency: f64, channels: u16) -> Self {
+        SineSource {
+            frequency,
+            channels: channels.max(1),
+            position: 0,
+            limit: None,
+        }
+    }
+
+    /// End the stream after `frames` frames.
+    pub fn with_limit(mut self, frames: u64) -> Self {
+        self.limit = Some(frames);
+        self
+    }
+}
+
+impl AudioSource for SineSource {
+    fn next_frame(&mut self) -> Result<Option<AudioFrame>> {
+        if self
+            .limit
+            .is_some_and(|limit| self.position >= limit * FRAME_SAMPLES as u64)
+        {
+            return Ok(None);
+        }
+        let step = 2.0 * std::f64::consts::PI * self.frequency / SAMPLE_RATE as f64;
+        let mut samples = Vec::with_capacity(FRAME_SAMPLES * self.channels as usize);
+        for i in 0..FRAME_SAMPLES as u64 {
+            // Half scale, so the tone is clearly audible without clipping.
+            let value = ((self.position + i) as f64 * step).sin() * (i16::MAX / 2) as f64;
+            samples.extend(std::iter::repeat_n(value as i16, self.channels as usize));
+        }
+        self.position += FRAME_SAMPLES as u64;
+        Ok(Some(AudioFrame::new(samples, self.channels)))
+    }
+}
+
+/// Plays a 16-bit PCM WAV file recorded at [`SAMPLE_RATE`].
+pub struct WavSource {
+    reader: BufReader<File>,
+    channels: u16,
+    remaining: usize,
+}
+
+impl WavSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            bail!("{:?} is not a WAV file", path);
+        }
+        let mut channels = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader
+                .read_exact(&mut chunk)
+                .with_context(|| format!("{:?} has no data chunk", path))?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as usize;
+            match &chunk[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0u8; size];
+                    reader.read_exact(&mut fmt)?;
+                    if fmt.len() < 16 {
+                        bail!("{:?} has a truncated format chunk", path);
+                    }
+                    let format = u16::from_le_bytes([fmt[0], fmt[1]]);
+                    let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                    let bits = u16::from_le_bytes([fmt[14], fmt[15]]);
+                    if format != 1 || bits != 16 || rate != SAMPLE_RATE {
+                        bail!(
+                            "{:?} must be 16-bit PCM at {} Hz, found format {} with {} bits at {} Hz",
+                            path,
+                            SAMPLE_RATE,
+                            format,
+                            bits,
+                            rate
+                        );
+                    }
+                    channels = Some(u16::from_le_bytes([fmt[2], fmt[3]]).max(1));
+                }
+                b"data" => {
+                    let Some(channels) = channels else {
+                        bail!("{:?} has no format chunk before its data", path);
+                    };
+                    return Ok(WavSource {
+                        reader,
+                        channels,
+                        remaining: size,
+                    });
+                }
+                // Chunks are padded to an even size.
+                _ => {
+                    std::io::copy(
+                        &mut (&mut reader).take((size + size % 2) as u64),
+                        &mut std::io::sink(),
+                    )?;
+                }
+            }
+        }
+    }
+}
+
+impl AudioSource for WavSource {
+    fn next_frame(&mut self) -> Result<Option<AudioFrame>> {
+        let len = (FRAME_SAMPLES * self.channels as usize * 2).min(self.remaining);
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut bytes = vec![0u8; len];
+        self.reader.read_exact(&mut bytes)?;
+        self.remaining -= len;
+        let mut samples: Vec<i16> = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        // The last frame is padded with silence.
+        samples.resize(FRAME_SAMPLES * self.channels as usize, 0);
+        Ok(Some(AudioFrame::new(samples, self.channels)))
+    }
+}
+
+/// Records what the system plays through `parec` (PulseAudio) or `pw-record` (PipeWire).
+pub struct SystemAudioSource {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl SystemAudioSource {
+    pub fn open() -> Result<Self> {
+        let rate = SAMPLE_RATE.to_string();
+        let channels = CHANNELS.to_string();
+        let candidates: [(&str, Vec<&str>); 2] = [
+            (
+                "parec",
+                vec![
+                    "--device=@DEFAULT_MONITOR@",
+                    "--format=s16le",
+                    "--rate",
+                    &rate,
+                    "--channels",
+                    &channels,
+                    "--raw",
+                ],
+            ),
+            (
+                "pw-record",
+                vec![
+                    "--format",
+                    "s16",
+                    "--rate",
+                    &rate,
+                    "--channels",
+                    &channels,
+                    "-",
+                ],
+            ),
+        ];
+        for (program, args) in candidates {
+            let Ok(mut child) = Command::new(program)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            else {
+                continue;
+            };
+            if let Some(stdout) = child.stdout.take() {
+                println!("Capturing system audio with {}", program);
+                return Ok(SystemAudioSource { child, stdout });
+            }
+            let _ = child.kill();
+        }
+        bail!("Neither parec nor pw-record is available to capture system audio")
+    }
+}
+
+impl AudioSource for SystemAudioSource {
+    fn next_frame(&mut self) -> Result<Option<AudioFrame>> {
+        let mut bytes = vec![0u8; FRAME_SAMPLES * CHANNELS as usize * 2];
+        match self.stdout.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let samples = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        Ok(Some(AudioFrame::new(samples, CHANNELS)))
+    }
+}
+
+impl Drop for SystemAudioSource {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Turns frames from the audio capture into the payload written to the audio track.
+pub trait AudioEncoder: Send {
+    fn encode(&mut self, frame: &AudioFrame) -> Result<Bytes>;
+}
+
+/// Encodes [`FRAME_DURATION`] frames to Opus in [`CHANNELS`], with in-band FEC so the
+/// receiver can recover a lost packet from the one after it.
+pub struct OpusEncoder {
+    encoder: Encoder,
+}
+
+impl OpusEncoder {
+    pub fn new() -> Result<Self> {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(BITRATE))?;
+        encoder.set_inband_fec(true)?;
+        // The encoder only spends bits on FEC when it expects some loss.
+        encoder.set_packet_loss_perc(10)?;
+        Ok(OpusEncoder { encoder })
+    }
+}
+
+impl AudioEncoder for OpusEncoder {
+    fn encode(&mut self, frame: &AudioFrame) -> Result<Bytes> {
+        if frame.sample_count() != FRAME_SAMPLES {
+            bail!(
+                "Opus frames must have {} samples, got {}",
+                FRAME_SAMPLES,
+                frame.sample_count()
+            );
+        }
+        let channels = frame.channels.max(1) as usize;
+        let mut pcm = Vec::with_capacity(FRAME_SAMPLES * CHANNELS as usize);
+        for sample in frame.samples.chunks_exact(channels) {
+            for channel in 0..CHANNELS as usize {
+                // Mono goes to every channel, extra channels are dropped.
+                pcm.push(sample[channel.min(channels - 1)]);
+            }
+        }
+        let mut packet = vec![0; MAX_PACKET_SIZE];
+        let len = self.encoder.encode(&pcm, &mut packet)?;
+        packet.truncate(len);
+        Ok(packet.into())
+    }
+}
+
+/// Derives audio `Sample` durations from sample counts.
+///
+/// Audio advances the RTP clock by exactly the samples it carries, so capture jitter never
+/// reaches the timestamps. A duration sets the timestamp of the frame after it, so each frame
+/// is written once the next one is captured. When the capture stalled for a frame or more
+/// in between, the gap is added to the duration, so the late frame is stamped at its
+/// capture time.
+#[derive(Debug, Default)]
+pub struct AudioClock {
+    origin: Option<Instant>,
+    emitted_ticks: u64,
+}
+
+impl AudioClock {
+    pub fn duration_until(&mut self, frame: &AudioFrame, next_captured_at: Instant) -> Duration {
+        let origin = *self.origin.get_or_insert(frame.captured_at);
+        let elapsed = next_captured_at.saturating_duration_since(origin);
+        let next = (elapsed.as_nanos() * SAMPLE_RATE as u128 / 1_000_000_000) as u64;
+        let samples = frame.sample_count() as u64;
+        let ticks = if next >= self.emitted_ticks + 2 * samples {
+            next - self.emitted_ticks
+        } else {
+            samples
+        };
+        self.emitted_ticks += ticks;
+        // Rounded up so the packetizer's truncating conversion back to ticks is lossless.
+        let nanos = (ticks as u128 * 1_000_000_000).div_ceil(SAMPLE_RATE as u128);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+static AV_SYNC: OnceLock<AvSync> = OnceLock::new();
+
+/// Lip sync of this process, fed by the video writer and read by the audio writer.
+pub fn av_sync() -> &'static AvSync {
+    AV_SYNC.get_or_init(AvSync::default)
+}
+
+/// Keeps audio and video equally far behind their capture.
+///
+/// Both tracks are stamped from capture time, but the sender reports a receiver syncs them
+/// with map RTP time to the time a packet is sent. Video spends longer in compression and
+/// encoding, so audio is held back by the latest video latency, up to [`MAX_SYNC_DELAY`].
+#[derive(Debug, Default)]
+pub struct AvSync {
+    video_latency_nanos: AtomicU64,
+}
+
+impl AvSync {
+    /// Record that the video frame captured at `captured_at` was just written.
+    pub fn record_video(&self, captured_at: Instant) {
+        let latency = captured_at.elapsed().as_nanos() as u64;
+        self.video_latency_nanos.store(latency, Ordering::Relaxed);
+    }
+
+    pub fn video_latency(&self) -> Duration {
+        Duration::from_nanos(self.video_latency_nanos.load(Ordering::Relaxed))
+    }
+
+    /// When the audio frame captured at `captured_at` is due to be written.
+    pub fn audio_due(&self, captured_at: Instant) -> Instant {
+        captured_at + self.video_latency().min(MAX_SYNC_DELAY)
+    }
+}
+
+/// Controls a running audio capture: a source thread reading frames and a writer thread
+/// sending them into [`RTC_AUDIO_TRACK`].
+///
+/// Dropping the handle stops the capture and joins both threads.
+#[must_use = "dropping the handle stops the capture"]
+pub struct AudioHandle {
+    stop: Arc<AtomicBool>,
+    frames_sent: FrameCounter,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl AudioHandle {
+    /// Start sending frames from the source built by `open_source`, encoded by `encoder`.
+    ///
+    /// The source is opened on the source thread; an error opening it is returned here.
+    pub fn start<F, S>(open_source: F, encoder: Box<dyn AudioEncoder>) -> Result<AudioHandle>
+    where
+        F: FnOnce() -> Result<S> + Send + 'static,
+        S: AudioSource,
+    {
+        let Some(track) = RTC_AUDIO_TRACK.get().cloned() else {
+            bail!("Audio track is not initialized");
+        };
+        if AUDIO_RUNNING.swap(true, Ordering::SeqCst) {
+            bail!("An audio capture is already running");
+        }
+        let mut handle = AudioHandle {
+            stop: Arc::new(AtomicBool::new(false)),
+            frames_sent: FrameCounter::default(),
+            threads: vec![],
+        };
+        let (opened_tx, opened_rx) = std_mpsc::channel::<Result<()>>();
+        let (frame_tx, frame_rx) = mpsc::unbounded_channel::<AudioFrame>();
+        let stop = handle.stop.clone();
+        handle.threads.push(thread::spawn(move || {
+            let source = match open_source() {
+                Ok(source) => {
+                    let _ = opened_tx.send(Ok(()));
+                    source
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            source_loop(source, frame_tx, &stop);
+        }));
+        match opened_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                handle.shutdown();
+                bail!("Failed to open audio source with : {:?}", e)
+            }
+            Err(e) => {
+                handle.shutdown();
+                bail!("Audio thread exited before opening the source: {:?}", e)
+            }
+        }
+
+        println!("Audio capture loop will be started");
+        let frames_sent = handle.frames_sent.clone();
+        handle.threads.push(thread::spawn(move || {
+            match Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime.block_on(write_loop(track, encoder, frame_rx, frames_sent)),
+                Err(e) => eprintln!("Failed to start audio writer runtime: {}", e),
+            }
+        }));
+        Ok(handle)
+    }
+
+    /// Counter of audio frames written to the track.
+    pub fn frames_sent(&self) -> FrameCounter {
+        self.frames_sent.clone()
+    }
+
+    /// Whether the source thread has ended, e.g. because its source ran out of frames.
+    pub fn is_finished(&self) -> bool {
+        self.threads.first().is_none_or(|t| t.is_finished())
+    }
+
+    pub fn stop(mut self) -> Result<()> {
+        if self.shutdown() {
+            bail!("An audio thread panicked");
+        }
+        Ok(())
+    }
+
+    /// Returns whether a thread panicked.
+    fn shutdown(&mut self) -> bool {
+        if self.stop.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let mut panicked = false;
+        for thread in self.threads.drain(..) {
+            panicked |= thread.join().is_err();
+        }
+        AUDIO_RUNNING.store(false, Ordering::SeqCst);
+        panicked
+    }
+}
+
+impl Drop for AudioHandle {
+    fn drop(&mut self) {
+        if self.shutdown() {
+            eprintln!("Error stopping audio capture: a thread panicked");
+        }
+    }
+}
+
+fn source_loop<S: AudioSource>(
+    mut source: S,
+    frames: mpsc::UnboundedSender<AudioFrame>,
+    stop: &AtomicBool,
+) {
+    let started = Instant::now();
+    let mut produced = Duration::ZERO;
+    while !stop.load(Ordering::SeqCst) {
+        // Live sources block on their own; this only paces synthetic ones.
+        if let Some(wait) = (started + produced).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        let frame = match source.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                println!("Audio source ended");
+                break;
+            }
+            Err(e) => {
+                eprintln!("Error capturing audio {}", e);
+                break;
+            }
+        };
+        produced += Duration::from_secs_f64(frame.sample_count() as f64 / SAMPLE_RATE as f64);
+        if frames.send(frame).is_err() {
+            break;
+        }
+    }
+}
+
+async fn write_loop(
+    track: Arc<TrackLocalStaticSample>,
+    mut encoder: Box<dyn AudioEncoder>,
+    mut frames: mpsc::UnboundedReceiver<AudioFrame>,
+    frames_sent: FrameCounter,
+) {
+    let mut clock = AudioClock::default();
+    let mut held: Option<AudioFrame> = None;
+    while let Some(next) = frames.recv().await {
+        if !sharing_allowed() {
+            held = None;
+            continue;
+        }
+        let next_captured_at = next.captured_at;
+        let Some(frame) = held.replace(next) else {
+            continue;
+        };
+        let duration = clock.duration_until(&frame, next_captured_at);
+        let data = match encoder.encode(&frame) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error encoding audio {}", e);
+                continue;
+            }
+        };
+        tokio::time::sleep_until(av_sync().audio_due(frame.captured_at).into()).await;
+        let sample = Sample {
+            data,
+            duration,
+            timestamp: SystemTime::now() - frame.captured_at.elapsed(),
+            ..Default::default()
+        };
+        match track.write_sample(&sample).await {
+            Ok(_) => {
+                frames_sent.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => eprintln!("Error sending audio {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_frame(samples: usize) -> AudioFrame {
+        AudioFrame::new(vec![0; samples], 1)
+    }
+
+    fn packetizer_ticks(duration: Duration) -> u64 {
+        (duration.as_secs_f64() * SAMPLE_RATE as f64) as u64
+    }
+
+    #[test]
+    fn timestamps_advance_by_samples_and_skip_stalls() {
+        let mut clock = AudioClock::default();
+        let mut source = SineSource::new(440.0, 1);
+        let start = Instant::now();
+        let mut frame = source.next_frame().unwrap().unwrap();
+        // Jitter in capture times does not reach the timestamps.
+        let captured = |i: u32| start + FRAME_DURATION * i + Duration::from_millis(i as u64 % 7);
+        let mut rtp = 0;
+        for i in 0..49 {
+            frame.captured_at = captured(i);
+            rtp += packetizer_ticks(clock.duration_until(&frame, captured(i + 1)));
+        }
+        assert_eq!(rtp, 49 * FRAME_SAMPLES as u64);
+
+        // After a 120ms stall the late frame is stamped at its capture time.
+        frame.captured_at = captured(49);
+        rtp += packetizer_ticks(clock.duration_until(&frame, start + Duration::from_millis(1100)));
+        assert_eq!(rtp, 1100 * 48);
+    }
+
+    #[test]
+    fn wav_source_reads_frames_and_pads_the_last() {
+        let dir = std::env::temp_dir().join(format!("audio-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tone.wav");
+        let samples: Vec<i16> = (0..FRAME_SAMPLES as i16 + 10).collect();
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend(b"LIST\x02\0\0\0ab");
+        wav.extend(b"fmt \x10\0\0\0");
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(SAMPLE_RATE.to_le_bytes());
+        wav.extend((SAMPLE_RATE * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend((data.len() as u32).to_le_bytes());
+        wav.extend(&data);
+        std::fs::write(&path, wav).unwrap();
+
+        let mut source = WavSource::open(&path).unwrap();
+        let first = source.next_frame().unwrap().unwrap();
+        assert_eq!(first.samples[..3], [0, 1, 2]);
+        let last = source.next_frame().unwrap().unwrap();
+        assert_eq!(last.sample_count(), FRAME_SAMPLES);
+        assert_eq!(last.samples[9], FRAME_SAMPLES as i16 + 9);
+        assert_eq!(last.samples[10], 0);
+        assert!(source.next_frame().unwrap().is_none());
+
+        let encoded = OpusEncoder::new().unwrap().encode(&first).unwrap();
+        assert!(!encoded.is_empty() && encoded.len() <= MAX_PACKET_SIZE);
+        assert!(OpusEncoder::new()
+            .unwrap()
+            .encode(&source_frame(10))
+            .is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
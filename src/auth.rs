@@ -0,0 +1,226 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{consent::sharing_allowed, model::SdpOfferAnswer, RTC_AUTHENTICATOR};
+
+const HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+/// What an authenticated client may do with the session.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// Watch the screen and hear the audio.
+    View,
+    /// Also drive input, write the clipboard and send files.
+    Control,
+}
+
+/// Payload of a signaling token.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    /// Client id the token was issued to.
+    pub sub: String,
+    pub role: Role,
+    /// Expiry in seconds since the Unix epoch.
+    pub exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenHeader {
+    alg: String,
+}
+
+/// Signs and verifies HS256 JSON Web Tokens with a shared secret.
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        TokenSigner {
+            secret: secret.as_ref().to_vec(),
+        }
+    }
+
+    pub fn sign(&self, claims: &Claims) -> String {
+        let header = URL_SAFE_NO_PAD.encode(HEADER);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signed = format!("{}.{}", header, payload);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&signed).finalize().into_bytes());
+        format!("{}.{}", signed, signature)
+    }
+
+    /// The claims of `token`, if its signature is ours and it has not expired at `now`,
+    /// in seconds since the Unix epoch.
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims> {
+        let Some((signed, signature)) = token.rsplit_once('.') else {
+            bail!("Token is not a JSON Web Token");
+        };
+        let Some((header, payload)) = signed.split_once('.') else {
+            bail!("Token is not a JSON Web Token");
+        };
+        let header: TokenHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+        // Anything else, "none" in particular, would let the token choose how it is checked.
+        if header.alg != "HS256" {
+            bail!("Token is signed with {} instead of HS256", header.alg);
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature)?;
+        self.mac(signed)
+            .verify_slice(&signature)
+            .context("Token signature does not match")?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+        if claims.exp <= now {
+            bail!("Token of {} has expired", claims.sub);
+        }
+        Ok(claims)
+    }
+
+    fn mac(&self, signed: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes any key");
+        mac.update(signed.as_bytes());
+        mac
+    }
+}
+
+/// A client that passed [`Authenticator::authenticate`], with the role it was granted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub client_id: String,
+    pub role: Role,
+}
+
+/// Checks the token of signaling messages against an allow-list of clients.
+///
+/// A client gets the lesser of the role in its token and the role it is allowed.
+pub struct Authenticator {
+    signer: TokenSigner,
+    allowed: HashMap<String, Role>,
+}
+
+impl Authenticator {
+    pub fn new(signer: TokenSigner) -> Self {
+        Authenticator {
+            signer,
+            allowed: HashMap::new(),
+        }
+    }
+
+    /// Let `client_id` in, with at most `role`.
+    pub fn allow(mut self, client_id: &str, role: Role) -> Self {
+        self.allowed.insert(client_id.to_string(), role);
+        self
+    }
+
+    pub fn authenticate(&self, message: &SdpOfferAnswer) -> Result<Session> {
+        let Some(token) = &message.token else {
+            bail!("Signaling message carries no token");
+        };
+        let Some(client_id) = &message.client_id else {
+            bail!("Signaling message carries no client id");
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = self.signer.verify(token, now)?;
+        if &claims.sub != client_id {
+            bail!("Token of {} was presented by {}", claims.sub, client_id);
+        }
+        let Some(allowed) = self.allowed.get(client_id) else {
+            bail!("Client {} is not allowed", client_id);
+        };
+        Ok(Session {
+            client_id: client_id.clone(),
+            role: claims.role.min(*allowed),
+        })
+    }
+}
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// The client the current session was answered for, once authenticated.
+pub fn current_session() -> Option<Session> {
+    SESSION.lock().unwrap().clone()
+}
+
+pub(crate) fn set_session(session: Option<Session>) {
+    *SESSION.lock().unwrap() = session;
+}
+
+/// Whether the session may do what `role` allows. Without [`RTC_AUTHENTICATOR`] every
+/// session may do everything, as before authentication existed, unless the local user
+/// revoked it.
+pub fn permits(role: Role) -> bool {
+    if !sharing_allowed() {
+        return false;
+    }
+    if RTC_AUTHENTICATOR.get().is_none() {
+        return true;
+    }
+    current_session().is_some_and(|session| session.role >= role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(sub: &str, role: Role, exp: u64) -> Claims {
+        Claims {
+            sub: sub.to_string(),
+            role,
+            exp,
+        }
+    }
+
+    #[test]
+    fn tokens_are_checked_for_signature_algorithm_and_expiry() {
+        let signer = TokenSigner::new("secret");
+        let token = signer.sign(&claims("admin", Role::Control, 2000));
+        assert_eq!(signer.verify(&token, 1000).unwrap().role, Role::Control);
+        assert!(signer.verify(&token, 2000).is_err());
+        assert!(TokenSigner::new("other").verify(&token, 1000).is_err());
+
+        // Swapping in a payload with more rights breaks the signature.
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"admin","role":"control","exp":9999}"#);
+        let tampered = format!("{}.{}.{}", parts[0], forged, parts[2]);
+        assert!(signer.verify(&tampered, 1000).is_err());
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            parts[1]
+        );
+        assert!(signer.verify(&unsigned, 1000).is_err());
+    }
+
+    #[test]
+    fn clients_get_at_most_their_allowed_role() {
+        let signer = TokenSigner::new("secret");
+        let far = u64::MAX;
+        let token = signer.sign(&claims("viewer", Role::Control, far));
+        let authenticator = Authenticator::new(TokenSigner::new("secret"))
+            .allow("viewer", Role::View)
+            .allow("admin", Role::Control);
+        let message = |client: &str, token: &str| {
+            SdpOfferAnswer::new(None, None, Some(client.to_string())).with_token(token.to_string())
+        };
+
+        let session = authenticator
+            .authenticate(&message("viewer", &token))
+            .unwrap();
+        assert_eq!(session.role, Role::View);
+        // A token only works for the client it was issued to.
+        assert!(authenticator
+            .authenticate(&message("admin", &token))
+            .is_err());
+        let stranger = signer.sign(&claims("stranger", Role::View, far));
+        assert!(authenticator
+            .authenticate(&message("stranger", &stranger))
+            .is_err());
+        let unsigned = SdpOfferAnswer::new(None, None, Some("admin".to_string()));
+        assert!(authenticator.authenticate(&unsigned).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+use anyhow::{bail, Result};
+use image::imageops::FilterType;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc as std_mpsc, Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
+};
+use tokio::{
+    runtime::Builder,
+    sync::{mpsc, watch, Notify},
+};
+use webrtc::{media::Sample, rtp_transceiver::rtp_sender::RTCRtpSender};
+
+use crate::{
+    audio::av_sync,
+    broad_cast::{get_client_boradcast_enable, set_client_boradcast_enable},
+    consent::sharing_allowed,
+    cursor::{
+        composite_cursor, default_cursor_provider, CursorMessage, CursorMessenger, CursorMode,
+    },
+    damage::{DamageConfig, DamageTracker},
+    encoder::{KeyframePolicy, KeyframeRequests, KeyframeStats, PassthroughEncoder, VideoEncoder},
+    frame_bus::{FrameBus, FrameFormat, FrameSubscriber, SubscriberStats, VideoFrame},
+    frame_source::FrameSource,
+    metrics::metrics,
+    rate_control::{ContentHint, RateBounds, RateController},
+    screen_capture::compress_damaged_frame,
+    simulcast::{bitrate_shares, scale_frame, video_tracks, SimulcastLayer},
+    stats::FrameCounter,
+    timing::{FrameRate, SampleClock},
+    RTC_CURSOR_CHANNEL, RTC_LAYER_TRACKS, RTC_SENDER,
+};
+
+/// Only one capture may write to [`crate::RTC_TRACK`] at a time.
+static CAPTURE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Settings of a capture started with [`CaptureHandle::start`].
+#[derive(Debug, Clone, Default)]
+pub struct CaptureConfig {
+    pub damage: DamageConfig,
+    pub cursor: CursorMode,
+    /// Target capture rate; keep a clone to change it while the loop runs.
+    ///
+    /// Once RTCP feedback arrives the rate controller takes over within `rate`.
+    pub frame_rate: FrameRate,
+    /// Bounds for adapting bitrate, frame rate and output height to the network. The bitrate
+    /// bounds do nothing until a real encoder replaces [`PassthroughEncoder`].
+    pub rate: RateBounds,
+    /// How keyframe requests from the viewer are answered.
+    pub keyframes: KeyframePolicy,
+    /// Tune for what the screen shows; `None` keeps `rate` as it is.
+    pub content_hint: Option<ContentHint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+struct Control {
+    state: Mutex<CaptureState>,
+    changed: Condvar,
+    last_error: Mutex<Option<String>>,
+    error_count: Mutex<u64>,
+}
+
+impl Control {
+    fn set_state(&self, state: CaptureState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != CaptureState::Stopped {
+            *current = state;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Block while paused; returns `false` once the capture has been stopped.
+    fn wait_while_paused(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let state = self
+            .changed
+            .wait_while(state, |s| *s == CaptureState::Paused)
+            .unwrap();
+        *state == CaptureState::Running
+    }
+
+    fn report(&self, error: String) {
+        eprintln!("{}", error);
+        *self.last_error.lock().unwrap() = Some(error);
+        *self.error_count.lock().unwrap() += 1;
+    }
+}
+
+/// Controls a running capture: a capture thread publishing to a [`FrameBus`] and a
+/// writer thread sending the frames it subscribes to into [`crate::RTC_TRACK`].
+///
+/// Dropping the handle stops the capture and joins both threads.
+#[must_use = "dropping the handle stops the capture"]
+pub struct CaptureHandle {
+    control: Arc<Control>,
+    frame_rate: FrameRate,
+    rate_controller: RateController,
+    keyframes: Arc<KeyframeRequests>,
+    frames_sent: FrameCounter,
+    frame_bus: FrameBus,
+    stop_tx: watch::Sender<bool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl CaptureHandle {
+    /// Start capturing frames from the source built by `open_source`.
+    ///
+    /// The source is opened on the capture thread; an error opening it is returned here.
+    /// Frames in which damage detection finds no change are skipped, except for keepalive frames.
+    pub fn start<F, S>(open_source: F, config: CaptureConfig) -> Result<CaptureHandle>
+    where
+        F: FnOnce() -> Result<S> + Send + 'static,
+        S: FrameSource,
+    {
+        if CAPTURE_RUNNING.swap(true, Ordering::SeqCst) {
+            bail!("A screen capture is already running");
+        }
+        set_client_boradcast_enable(true);
+
+        let control = Arc::new(Control {
+            state: Mutex::new(CaptureState::Running),
+            changed: Condvar::new(),
+            last_error: Mutex::new(None),
+            error_count: Mutex::new(0),
+        });
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let mut handle = CaptureHandle {
+            control: control.clone(),
+            frame_rate: config.frame_rate.clone(),
+            rate_controller: RateController::new(config.rate, config.frame_rate.clone()),
+            keyframes: Arc::new(KeyframeRequests::new(config.keyframes)),
+            frames_sent: FrameCounter::default(),
+            frame_bus: FrameBus::new(),
+            stop_tx,
+            threads: vec![],
+        };
+
+        if config.content_hint.is_some() {
+            handle.rate_controller.set_content_hint(config.content_hint);
+        }
+        let (opened_tx, opened_rx) = std_mpsc::channel::<Result<()>>();
+        let (cursor_tx, cursor_rx) = mpsc::unbounded_channel::<CursorMessage>();
+        // Subscribe before the first frame can be published so the writer sees it.
+        let frames = handle.frame_bus.subscribe("track");
+        metrics().set_frame_bus(Some(handle.frame_bus.clone()));
+        let bus = handle.frame_bus.clone();
+        let rate = handle.rate_controller.clone();
+        let capture_control = control.clone();
+        handle.threads.push(thread::spawn(move || {
+            let source = match open_source() {
+                Ok(source) => {
+                    let _ = opened_tx.send(Ok(()));
+                    source
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            capture_loop(source, config, bus, rate, cursor_tx, &capture_control);
+        }));
+        match opened_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => bail!("Failed to open frame source with : {:?}", e),
+            Err(e) => bail!(
+                "Capture thread exited before opening the frame source: {:?}",
+                e
+            ),
+        }
+
+        println!("Screen capture loop will be started");
+        let writer = TrackWriter {
+            encoders: vec![],
+            content_hint: None,
+            sample_clock: None,
+            held: None,
+            rate: handle.rate_controller.clone(),
+            keyframes: handle.keyframes.clone(),
+            frames_sent: handle.frames_sent.clone(),
+        };
+        let writer_control = control;
+        handle.threads.push(thread::spawn(move || {
+            match Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime.block_on(async move {
+                    let force_keyframe = Arc::new(Notify::new());
+                    if let Some(sender) = RTC_SENDER.get() {
+                        // Every simulcast layer has its own RTCP stream.
+                        let rids: Vec<Option<String>> = match RTC_LAYER_TRACKS.get() {
+                            Some(layers) => {
+                                layers.iter().map(|l| Some(l.layer.rid.clone())).collect()
+                            }
+                            None => vec![None],
+                        };
+                        for rid in rids {
+                            tokio::spawn(read_rtcp_loop(
+                                sender.clone(),
+                                rid,
+                                writer.rate.clone(),
+                                writer.keyframes.clone(),
+                                force_keyframe.clone(),
+                            ));
+                        }
+                    }
+                    write_loop(writer, frames, cursor_rx, force_keyframe, stop_rx).await
+                }),
+                Err(e) => writer_control.report(format!("Failed to start writer runtime: {}", e)),
+            }
+        }));
+        Ok(handle)
+    }
+
+    pub fn pause(&self) {
+        self.control.set_state(CaptureState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.control.set_state(CaptureState::Running);
+    }
+
+    pub fn state(&self) -> CaptureState {
+        *self.control.state.lock().unwrap()
+    }
+
+    /// Handle to the frame rate of the running capture.
+    pub fn frame_rate(&self) -> FrameRate {
+        self.frame_rate.clone()
+    }
+
+    /// Rate controller adapting the capture to RTCP feedback.
+    pub fn rate_controller(&self) -> RateController {
+        self.rate_controller.clone()
+    }
+
+    /// Retune the running capture for what the screen shows, see
+    /// [`RateController::set_content_hint`].
+    pub fn set_content_hint(&self, hint: Option<ContentHint>) {
+        self.rate_controller.set_content_hint(hint);
+    }
+
+    /// Keyframe requests received from the viewer and keyframes sent.
+    pub fn keyframe_stats(&self) -> KeyframeStats {
+        self.keyframes.stats()
+    }
+
+    /// Counter of frames written to the track, for [`crate::stats::StatsCollector`].
+    pub fn frames_sent(&self) -> FrameCounter {
+        self.frames_sent.clone()
+    }
+
+    /// Bus the captured frames are published on, for additional subscribers.
+    pub fn frame_bus(&self) -> FrameBus {
+        self.frame_bus.clone()
+    }
+
+    /// Delivery counters of every subscriber of the capture's frame bus.
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.frame_bus.subscriber_stats()
+    }
+
+    /// Whether the capture thread has ended, e.g. because its source ran out of frames.
+    pub fn is_finished(&self) -> bool {
+        self.threads.first().is_none_or(|t| t.is_finished())
+    }
+
+    /// Most recent capture, compression or writer error.
+    pub fn last_error(&self) -> Option<String> {
+        self.control.last_error.lock().unwrap().clone()
+    }
+
+    pub fn error_count(&self) -> u64 {
+        *self.control.error_count.lock().unwrap()
+    }
+
+    /// Stop capturing and wait for both threads, failing if one of them panicked.
+    pub fn stop(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        if self.threads.is_empty() {
+            return Ok(());
+        }
+        *self.control.state.lock().unwrap() = CaptureState::Stopped;
+        self.control.changed.notify_all();
+        let _ = self.stop_tx.send(true);
+        // A source waiting for the screen to change polls this flag.
+        set_client_boradcast_enable(false);
+
+        let mut panicked = false;
+        for thread in self.threads.drain(..) {
+            panicked |= thread.join().is_err();
+        }
+        metrics().set_frame_bus(None);
+        CAPTURE_RUNNING.store(false, Ordering::SeqCst);
+        if panicked {
+            bail!("A capture thread panicked");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CaptureHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            eprintln!("Error stopping screen capture: {:?}", e);
+        }
+    }
+}
+
+fn capture_loop<S: FrameSource>(
+    mut source: S,
+    config: CaptureConfig,
+    bus: FrameBus,
+    rate: RateController,
+    cursor_tx: mpsc::UnboundedSender<CursorMessage>,
+    control: &Control,
+) {
+    let mut tracker = DamageTracker::new(config.damage);
+    let mut last_frame: Option<Arc<VideoFrame>> = None;
+    let mut cursor_provider = match config.cursor {
+        CursorMode::Off => None,
+        _ => default_cursor_provider().unwrap_or_else(|e| {
+            control.report(format!("Failed to open cursor provider: {:?}", e));
+            None
+        }),
+    };
+    let mut cursor_messenger = CursorMessenger::default();
+    loop {
+        if !control.wait_while_paused() || !get_client_boradcast_enable() {
+            break;
+        }
+        let start_time = Instant::now();
+        let frame_time = config.frame_rate.interval();
+
+        match source.next_frame() {
+            Ok(Some(mut frame)) => {
+                if let Some(provider) = cursor_provider.as_mut() {
+                    let cursor = provider.cursor().unwrap_or_else(|e| {
+                        control.report(format!("Failed to read cursor: {:?}", e));
+                        None
+                    });
+                    match config.cursor {
+                        CursorMode::Overlay => {
+                            if let Some(cursor) = cursor.as_ref() {
+                                composite_cursor(&mut frame, cursor);
+                            }
+                        }
+                        CursorMode::DataChannel => {
+                            match cursor_messenger.updates(
+                                cursor.as_ref(),
+                                frame.width,
+                                frame.height,
+                                Instant::now(),
+                            ) {
+                                Ok(messages) => messages.into_iter().for_each(|m| {
+                                    let _ = cursor_tx.send(m);
+                                }),
+                                Err(e) => {
+                                    control.report(format!("Failed to encode cursor: {:?}", e))
+                                }
+                            }
+                        }
+                        CursorMode::Off => {}
+                    }
+                }
+                let captured_at = frame.captured_at;
+                let filter = rate
+                    .content_hint()
+                    .map_or(FilterType::Lanczos3, ContentHint::scale_filter);
+                match compress_damaged_frame(frame, &mut tracker, rate.settings().height, filter) {
+                    Ok(Some((compressed, dirty))) => {
+                        let mut frame = VideoFrame::new(
+                            compressed.data,
+                            compressed.width,
+                            compressed.height,
+                            FrameFormat::Rgba,
+                            captured_at,
+                        );
+                        frame.dirty = dirty;
+                        last_frame = Some(bus.publish(frame));
+                        metrics().record_frame_captured();
+                        tracker.mark_sent(captured_at);
+                    }
+                    Ok(None) => {
+                        // Static screen: resend the previous frame only at the keepalive rate.
+                        if let Some(previous) = last_frame.as_ref() {
+                            if tracker.keepalive_due(captured_at) {
+                                let mut frame = VideoFrame::clone(previous);
+                                frame.captured_at = captured_at;
+                                frame.dirty = vec![];
+                                last_frame = Some(bus.publish(frame));
+                                metrics().record_frame_captured();
+                                tracker.mark_sent(captured_at);
+                            }
+                        }
+                    }
+                    Err(e) => control.report(format!("Failed to compress frame: {:?}", e)),
+                }
+            }
+            Ok(None) => {
+                println!("Frame source ended");
+                break;
+            }
+            Err(e) => control.report(format!("Failed to capture screen: {:?}", e)),
+        }
+
+        let elapsed = start_time.elapsed();
+        if elapsed < frame_time {
+            thread::sleep(frame_time - elapsed);
+        }
+    }
+}
+
+struct LayerEncoder {
+    encoder: PassthroughEncoder,
+    bitrate: Option<u32>,
+}
+
+/// Encodes frames and writes them to [`crate::RTC_TRACK`], or to every simulcast layer.
+struct TrackWriter {
+    encoders: Vec<LayerEncoder>,
+    /// Hint the encoders were last tuned for.
+    content_hint: Option<ContentHint>,
+    sample_clock: Option<SampleClock>,
+    /// Latest frame, written once the next capture gives its duration.
+    held: Option<Arc<VideoFrame>>,
+    rate: RateController,
+    keyframes: Arc<KeyframeRequests>,
+    frames_sent: FrameCounter,
+}
+
+impl TrackWriter {
+    fn request_keyframe(&mut self) {
+        for layer in &mut self.encoders {
+            layer.encoder.request_keyframe();
+        }
+    }
+
+    async fn write(&mut self, frame: Arc<VideoFrame>) {
+        if frame.data.is_empty() {
+            println!("Received empty buffer from broadcast channel");
+            return;
+        }
+        if !sharing_allowed() {
+            self.held = None;
+            return;
+        }
+        // A sample's duration sets the timestamp of the next one, so a frame goes out when
+        // the one after it is captured.
+        let next_captured_at = frame.captured_at;
+        if let Some(held) = self.held.replace(frame) {
+            self.send(&held, next_captured_at).await;
+        }
+    }
+
+    async fn send(&mut self, frame: &VideoFrame, next_captured_at: Instant) {
+        let tracks = video_tracks();
+        let Some(first) = tracks.first() else {
+            println!("RTC_TRACK is None, cannot send frame to WebRTC track");
+            return;
+        };
+        // Layers share capture times, so one clock serves them all.
+        let clock = self
+            .sample_clock
+            .get_or_insert_with(|| SampleClock::new(first.track.codec().clock_rate));
+        let duration = clock.duration_until(frame.captured_at, next_captured_at);
+        let timestamp = SystemTime::now() - frame.captured_at.elapsed();
+
+        let layers: Vec<SimulcastLayer> = tracks.iter().map(|t| t.layer.clone()).collect();
+        let total_bitrate = self.rate.settings().bitrate;
+        let hint = self.rate.content_hint();
+        if hint != self.content_hint {
+            // Start over from the configured parameters rather than undo a hint.
+            self.encoders.clear();
+            self.content_hint = hint;
+        }
+        self.encoders.resize_with(tracks.len(), || {
+            let mut encoder = PassthroughEncoder::default();
+            if let Some(hint) = hint {
+                encoder.set_content_hint(hint);
+            }
+            LayerEncoder {
+                encoder,
+                bitrate: None,
+            }
+        });
+        let (mut sent, mut keyframe) = (false, false);
+        for ((track, layer), share) in tracks
+            .iter()
+            .zip(self.encoders.iter_mut())
+            .zip(bitrate_shares(&layers))
+        {
+            let scaled;
+            let layer_frame = if track.layer.scale_down > 1 {
+                match scale_frame(frame, track.layer.scale_down) {
+                    Ok(frame) => {
+                        scaled = frame;
+                        &scaled
+                    }
+                    Err(e) => {
+                        eprintln!("Error scaling frame for layer {} {}", track.layer.rid, e);
+                        continue;
+                    }
+                }
+            } else {
+                frame
+            };
+            let target = (total_bitrate as f64 * share) as u32;
+            if layer.bitrate != Some(target) {
+                layer.encoder.set_bitrate(target);
+                layer.bitrate = Some(target);
+            }
+            let encode_start = Instant::now();
+            let encoded = layer.encoder.encode(layer_frame);
+            metrics().record_encode_time(encode_start.elapsed());
+            let encoded = match encoded {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    eprintln!("Error encoding frame {}", e);
+                    continue;
+                }
+            };
+            match track
+                .track
+                .write_sample(&Sample {
+                    data: encoded.data,
+                    duration,
+                    timestamp,
+                    ..Default::default()
+                })
+                .await
+            {
+                Ok(_) => {
+                    sent = true;
+                    keyframe |= encoded.keyframe;
+                }
+                Err(e) => eprintln!("Error sending fram {}", e),
+            }
+        }
+        if sent {
+            self.frames_sent.fetch_add(1, Ordering::Relaxed);
+            av_sync().record_video(frame.captured_at);
+            if keyframe {
+                self.keyframes.record_sent();
+            }
+            println!("Sent frame to WebRTC track");
+        }
+    }
+}
+
+async fn write_loop(
+    mut writer: TrackWriter,
+    mut frames: FrameSubscriber,
+    mut cursor_rx: mpsc::UnboundedReceiver<CursorMessage>,
+    force_keyframe: Arc<Notify>,
+    mut stop_rx: watch::Receiver<bool>,
+) {
+    let mut last_frame: Option<Arc<VideoFrame>> = None;
+
+    loop {
+        tokio::select! {
+            _ = stop_rx.changed() => break,
+            frame = frames.recv() => {
+                let Some(frame) = frame else {
+                    break;
+                };
+                writer.write(frame.clone()).await;
+                last_frame = Some(frame);
+            }
+            _ = force_keyframe.notified() => {
+                writer.request_keyframe();
+                // Resend the latest frame right away; on a static screen the next one may be
+                // a keepalive interval away. It also pushes out the held frame as the keyframe.
+                if let Some(previous) = last_frame.as_ref() {
+                    let mut frame = VideoFrame::clone(previous);
+                    frame.captured_at = Instant::now();
+                    writer.write(Arc::new(frame)).await;
+                }
+            }
+            Some(message) = cursor_rx.recv() => {
+                if !sharing_allowed() {
+                    continue;
+                }
+                if let Some(channel) = RTC_CURSOR_CHANNEL.get() {
+                    if let Err(e) = channel.send_text(message.to_json()).await {
+                        eprintln!("Error sending cursor update {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read RTCP from the video sender so its interceptors run; feed it to the rate controller and
+/// turn PLI/FIR into keyframe requests for the writer.
+async fn read_rtcp_loop(
+    sender: Arc<RTCRtpSender>,
+    rid: Option<String>,
+    rate: RateController,
+    keyframes: Arc<KeyframeRequests>,
+    force_keyframe: Arc<Notify>,
+) {
+    loop {
+        let packets = match rid.as_deref() {
+            Some(rid) => sender.read_rtcp_simulcast(rid).await,
+            None => sender.read_rtcp().await,
+        };
+        match packets {
+            Ok((packets, _)) => {
+                rate.on_rtcp(&packets);
+                if keyframes.on_rtcp(&packets, Instant::now()) {
+                    println!("Keyframe requested by the viewer");
+                    force_keyframe.notify_one();
+                }
+            }
+            Err(e) => {
+                println!("Stopped reading RTCP: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_source::TestPatternSource;
+    use std::time::Duration;
+
+    // Captures share RTC_TRACK, so only one may run at a time.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn capture_can_be_paused_stopped_and_restarted() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let config = CaptureConfig {
+            cursor: CursorMode::Off,
+            ..Default::default()
+        };
+        let handle =
+            CaptureHandle::start(|| Ok(TestPatternSource::new(64, 36)), config.clone()).unwrap();
+        assert!(
+            CaptureHandle::start(|| Ok(TestPatternSource::new(64, 36)), config.clone()).is_err()
+        );
+
+        handle.pause();
+        assert_eq!(handle.state(), CaptureState::Paused);
+        handle.resume();
+        assert_eq!(handle.state(), CaptureState::Running);
+        handle.stop().unwrap();
+
+        let handle =
+            CaptureHandle::start(|| Ok(TestPatternSource::new(64, 36).with_limit(2)), config)
+                .unwrap();
+        wait_until_finished(&handle);
+        assert_eq!(handle.error_count(), 0);
+    }
+
+    #[test]
+    fn first_frame_is_not_lost_during_start_up() {
+        let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let config = CaptureConfig {
+            cursor: CursorMode::Off,
+            ..Default::default()
+        };
+        let handle =
+            CaptureHandle::start(|| Ok(TestPatternSource::new(64, 36).with_limit(1)), config)
+                .unwrap();
+        wait_until_finished(&handle);
+
+        // The writer subscribed before capture began, and late subscribers get the retained frame.
+        let mut late = handle.frame_bus().subscribe("late");
+        assert_eq!(late.try_recv().unwrap().sequence, 0);
+        let track = &handle.subscriber_stats()[0];
+        assert_eq!((track.name.as_str(), track.dropped), ("track", 0));
+    }
+
+    fn wait_until_finished(handle: &CaptureHandle) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.is_finished());
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use webrtc::dtls_transport::dtls_fingerprint::RTCDtlsFingerprint;
+use webrtc::peer_connection::certificate::RTCCertificate;
+
+/// A fresh self-signed ECDSA P-256 certificate for DTLS.
+pub fn generate_certificate() -> Result<RTCCertificate> {
+    let key_pair = rcgen::KeyPair::generate()?;
+    Ok(RTCCertificate::from_key_pair(key_pair)?)
+}
+
+/// Read a certificate, with its private key, written by [`save_certificate`].
+pub fn load_certificate(path: impl AsRef<Path>) -> Result<RTCCertificate> {
+    let path = path.as_ref();
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("Error reading certificate {}", path.display()))?;
+    Ok(RTCCertificate::from_pem(&pem)?)
+}
+
+/// Write `certificate` with its private key as PEM, readable only by the owner.
+pub fn save_certificate(certificate: &RTCCertificate, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Error writing certificate {}", path.display()))?;
+    std::io::Write::write_all(&mut file, certificate.serialize_pem().as_bytes())?;
+    Ok(())
+}
+
+/// The certificate stored at `path`, or a new one saved there when there is none yet or
+/// it has expired. Keeping it lets viewers verify the same fingerprint every session.
+pub fn load_or_generate_certificate(path: impl AsRef<Path>) -> Result<RTCCertificate> {
+    let path = path.as_ref();
+    if path.exists() {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading certificate {}", path.display()))?;
+        if expires(&pem)? > SystemTime::now() {
+            return Ok(RTCCertificate::from_pem(&pem)?);
+        }
+        println!("Certificate {} has expired, replacing it", path.display());
+    }
+    let certificate = generate_certificate()?;
+    save_certificate(&certificate, path)?;
+    Ok(certificate)
+}
+
+// RTCCertificate keeps its expiry to itself; it is the leading EXPIRES block of its PEM.
+fn expires(pem: &str) -> Result<SystemTime> {
+    let block = pem::parse(pem.split("\n\n").next().unwrap_or_default())?;
+    let Ok(seconds) = <[u8; 8]>::try_from(block.contents()) else {
+        bail!("Certificate has no expiry");
+    };
+    Ok(UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(seconds)))
+}
+
+/// A DTLS certificate fingerprint as it appears in SDP, such as `sha-256 AB:CD:...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub algorithm: String,
+    /// Colon-separated hex bytes, upper case.
+    pub value: String,
+}
+
+impl Fingerprint {
+    pub fn new(algorithm: &str, value: &str) -> Self {
+        Fingerprint {
+            algorithm: algorithm.to_ascii_lowercase(),
+            value: value.to_ascii_uppercase(),
+        }
+    }
+
+    /// Parse the `<algorithm> <value>` form of an SDP `a=fingerprint` attribute.
+    pub fn parse(fingerprint: &str) -> Result<Self> {
+        let Some((algorithm, value)) = fingerprint.trim().split_once(' ') else {
+            bail!("Fingerprint {} has no algorithm", fingerprint);
+        };
+        let value = value.trim();
+        if value.is_empty()
+            || !value
+                .split(':')
+                .all(|byte| byte.len() == 2 && byte.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            bail!("Fingerprint {} is not colon-separated hex", fingerprint);
+        }
+        Ok(Fingerprint::new(algorithm, value))
+    }
+
+    /// Every fingerprint attribute of `sdp`, at session or media level.
+    pub fn from_sdp(sdp: &str) -> Result<Vec<Self>> {
+        sdp.lines()
+            .filter_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+            .map(Fingerprint::parse)
+            .collect()
+    }
+}
+
+impl From<&RTCDtlsFingerprint> for Fingerprint {
+    fn from(fingerprint: &RTCDtlsFingerprint) -> Self {
+        Fingerprint::new(&fingerprint.algorithm, &fingerprint.value)
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.algorithm, self.value)
+    }
+}
+
+/// Fail unless every fingerprint in `sdp` is `pinned`.
+///
+/// The DTLS handshake already checks the remote certificate against the fingerprint in
+/// the SDP; pinning that fingerprint keeps a signaling server from swapping in its own.
+pub fn verify_pinned_fingerprint(sdp: &str, pinned: &Fingerprint) -> Result<()> {
+    let fingerprints = Fingerprint::from_sdp(sdp)?;
+    if fingerprints.is_empty() {
+        bail!("Session description carries no fingerprint");
+    }
+    if let Some(other) = fingerprints.iter().find(|f| *f != pinned) {
+        bail!(
+            "Remote fingerprint {} does not match pinned {}",
+            other,
+            pinned
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificates_survive_a_round_trip_through_pem() {
+        let path =
+            std::env::temp_dir().join(format!("webrtc_client_cert_{}.pem", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let certificate = load_or_generate_certificate(&path).unwrap();
+        let loaded = load_or_generate_certificate(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let fingerprint = |c: &RTCCertificate| Fingerprint::from(&c.get_fingerprints()[0]);
+        assert_eq!(fingerprint(&loaded), fingerprint(&certificate));
+        assert_eq!(fingerprint(&certificate).algorithm, "sha-256");
+        assert_ne!(
+            fingerprint(&generate_certificate().unwrap()),
+            fingerprint(&certificate)
+        );
+    }
+
+    #[test]
+    fn only_the_pinned_fingerprint_passes() {
+        let pinned = Fingerprint::parse("sha-256 ab:cd:EF").unwrap();
+        let offer =
+            |value: &str| format!("v=0\r\na=fingerprint:sha-256 {}\r\nm=video 9\r\n", value);
+        assert!(verify_pinned_fingerprint(&offer("AB:CD:EF"), &pinned).is_ok());
+        assert!(verify_pinned_fingerprint(&offer("AB:CD:00"), &pinned).is_err());
+        // A media section may not bring a fingerprint of its own.
+        let mixed = offer("AB:CD:EF") + "a=fingerprint:sha-256 00:11:22\r\n";
+        assert!(verify_pinned_fingerprint(&mixed, &pinned).is_err());
+        assert!(verify_pinned_fingerprint("v=0\r\n", &pinned).is_err());
+        assert!(Fingerprint::parse("AB:CD").is_err());
+    }
+}
@@ -22,7 +22,8 @@
 // use crate::screen_capture::capture_screen;
 
 // const SIGNALING_SERVER: &str = "ws://127.0.0.1:8080"; // Modify if using WebSocket
-pub const FPS_LIMIT: f64 = 30.0;
+/// Capture frame rate used until a [`crate::timing::FrameRate`] is adjusted at runtime.
+pub const DEFAULT_FPS: f64 = 30.0;
 
 // /// Start WebRTC screen-sharing session
 // pub async fn start_screen_share() -> Result<()> {
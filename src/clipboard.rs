@@ -0,0 +1,404 @@
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use webrtc::data_channel::{
+    data_channel_message::DataChannelMessage, data_channel_state::RTCDataChannelState,
+    RTCDataChannel,
+};
+
+use crate::{
+    auth::{self, Role},
+    consent::sharing_allowed,
+};
+
+/// Label of the data channel carrying [`ClipboardMessage`]s both ways.
+pub const CLIPBOARD_CHANNEL: &str = "clipboard";
+
+/// Largest message webrtc-rs sends over a data channel.
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+/// What the clipboard holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClipboardContent {
+    Text(String),
+    /// PNG encoded image.
+    Image(Vec<u8>),
+}
+
+impl ClipboardContent {
+    fn len(&self) -> usize {
+        match self {
+            ClipboardContent::Text(text) => text.len(),
+            ClipboardContent::Image(png) => png.len(),
+        }
+    }
+}
+
+/// Clipboard content sent over the [`CLIPBOARD_CHANNEL`] data channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClipboardMessage {
+    Text {
+        text: String,
+    },
+    /// Base64 encoded PNG.
+    Image {
+        png: String,
+    },
+}
+
+impl ClipboardMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+impl From<&ClipboardContent> for ClipboardMessage {
+    fn from(content: &ClipboardContent) -> Self {
+        match content {
+            ClipboardContent::Text(text) => ClipboardMessage::Text { text: text.clone() },
+            ClipboardContent::Image(png) => ClipboardMessage::Image {
+                png: STANDARD.encode(png),
+            },
+        }
+    }
+}
+
+impl TryFrom<ClipboardMessage> for ClipboardContent {
+    type Error = anyhow::Error;
+
+    fn try_from(message: ClipboardMessage) -> Result<Self> {
+        Ok(match message {
+            ClipboardMessage::Text { text } => ClipboardContent::Text(text),
+            ClipboardMessage::Image { png } => ClipboardContent::Image(STANDARD.decode(png)?),
+        })
+    }
+}
+
+/// What the user agreed to share. Nothing is synchronized by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClipboardConfig {
+    /// Send local clipboard changes to the peer.
+    pub send: bool,
+    /// Let the peer replace the local clipboard.
+    pub receive: bool,
+    /// Synchronize images as well as text.
+    pub images: bool,
+    /// Larger text is not synchronized.
+    pub max_text_bytes: usize,
+    /// Larger PNGs are not synchronized; base64 must still fit in one message.
+    pub max_image_bytes: usize,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        ClipboardConfig {
+            send: false,
+            receive: false,
+            images: false,
+            max_text_bytes: 32 * 1024,
+            max_image_bytes: 46 * 1024,
+        }
+    }
+}
+
+impl ClipboardConfig {
+    fn allows(&self, content: &ClipboardContent) -> bool {
+        match content {
+            ClipboardContent::Text(text) => text.len() <= self.max_text_bytes,
+            ClipboardContent::Image(png) => self.images && png.len() <= self.max_image_bytes,
+        }
+    }
+}
+
+/// Reads and writes the local clipboard.
+pub trait ClipboardBackend: Send {
+    /// Current content, or `None` when the clipboard is empty or holds something else.
+    fn read(&mut self) -> Result<Option<ClipboardContent>>;
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()>;
+}
+
+/// Clipboard kept in memory, for tests. Clones share the content.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryClipboard(Arc<Mutex<Option<ClipboardContent>>>);
+
+impl MemoryClipboard {
+    pub fn get(&self) -> Option<ClipboardContent> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, content: ClipboardContent) {
+        *self.0.lock().unwrap() = Some(content);
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn read(&mut self) -> Result<Option<ClipboardContent>> {
+        Ok(self.get())
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()> {
+        self.set(content.clone());
+        Ok(())
+    }
+}
+
+/// Clipboard of the desktop session through `wl-clipboard` on Wayland or `xclip` on X11.
+#[derive(Debug, Clone, Copy)]
+pub struct CommandClipboard {
+    wayland: bool,
+}
+
+impl CommandClipboard {
+    pub fn detect() -> Self {
+        CommandClipboard {
+            wayland: std::env::var_os("WAYLAND_DISPLAY").is_some(),
+        }
+    }
+
+    fn paste(&self, mime_type: Option<&str>) -> Result<Option<Vec<u8>>> {
+        let mut command = if self.wayland {
+            let mut command = Command::new("wl-paste");
+            command.arg("--no-newline");
+            if let Some(mime_type) = mime_type {
+                command.args(["--type", mime_type]);
+            }
+            command
+        } else {
+            let mut command = Command::new("xclip");
+            command.args(["-selection", "clipboard", "-o"]);
+            if let Some(mime_type) = mime_type {
+                command.args(["-t", mime_type]);
+            }
+            command
+        };
+        let output = command.stderr(Stdio::null()).output()?;
+        // Both tools fail when nothing owns the clipboard.
+        Ok(output.status.success().then_some(output.stdout))
+    }
+
+    fn types(&self) -> Result<Vec<String>> {
+        let types = if self.wayland {
+            let output = Command::new("wl-paste")
+                .arg("--list-types")
+                .stderr(Stdio::null())
+                .output()?;
+            output.status.success().then_some(output.stdout)
+        } else {
+            self.paste(Some("TARGETS"))?
+        };
+        Ok(String::from_utf8_lossy(&types.unwrap_or_default())
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+impl ClipboardBackend for CommandClipboard {
+    fn read(&mut self) -> Result<Option<ClipboardContent>> {
+        if self.types()?.iter().any(|t| t == "image/png") {
+            return Ok(self.paste(Some("image/png"))?.map(ClipboardContent::Image));
+        }
+        Ok(self
+            .paste(None)?
+            .map(|text| ClipboardContent::Text(String::from_utf8_lossy(&text).into_owned())))
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<()> {
+        let (data, mime_type) = match content {
+            ClipboardContent::Text(text) => (text.as_bytes(), None),
+            ClipboardContent::Image(png) => (png.as_slice(), Some("image/png")),
+        };
+        let mut command = if self.wayland {
+            let mut command = Command::new("wl-copy");
+            if let Some(mime_type) = mime_type {
+                command.args(["--type", mime_type]);
+            }
+            command
+        } else {
+            let mut command = Command::new("xclip");
+            command.args(["-selection", "clipboard", "-i"]);
+            if let Some(mime_type) = mime_type {
+                command.args(["-t", mime_type]);
+            }
+            command
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(data)?;
+        }
+        // Both tools fork to keep serving the clipboard, so this returns right away.
+        if !child.wait()?.success() {
+            bail!("Failed to write the clipboard");
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the local clipboard and the peer's in sync within what [`ClipboardConfig`] allows.
+pub struct ClipboardSync {
+    config: ClipboardConfig,
+    backend: Box<dyn ClipboardBackend>,
+    /// Last content sent or received, so neither side echoes it back.
+    last: Option<ClipboardContent>,
+}
+
+impl ClipboardSync {
+    pub fn new(config: ClipboardConfig, backend: Box<dyn ClipboardBackend>) -> Self {
+        ClipboardSync {
+            config,
+            backend,
+            last: None,
+        }
+    }
+
+    /// The message to send when the local clipboard changed since the last call.
+    pub fn poll(&mut self) -> Result<Option<ClipboardMessage>> {
+        if !self.config.send || !sharing_allowed() {
+            return Ok(None);
+        }
+        let Some(content) = self.backend.read()? else {
+            return Ok(None);
+        };
+        if self.last.as_ref() == Some(&content) {
+            return Ok(None);
+        }
+        let message = ClipboardMessage::from(&content);
+        // Remember skipped content too, so it is not reconsidered every poll.
+        self.last = Some(content);
+        let content = self.last.as_ref().unwrap();
+        if !self.config.allows(content) || message.to_json().len() > MAX_MESSAGE_SIZE {
+            println!("Not sending {} byte clipboard content", content.len());
+            return Ok(None);
+        }
+        Ok(Some(message))
+    }
+
+    /// Apply a message received from the peer to the local clipboard.
+    pub fn apply(&mut self, text: &str) -> Result<()> {
+        if !self.config.receive {
+            bail!("Receiving the clipboard is not enabled");
+        }
+        if !auth::permits(Role::Control) {
+            bail!("Session is not authorized to write the clipboard");
+        }
+        let message: ClipboardMessage = serde_json::from_str(text)?;
+        let content = ClipboardContent::try_from(message)?;
+        if !self.config.allows(&content) {
+            bail!("Refusing {} byte clipboard content", content.len());
+        }
+        self.backend.write(&content)?;
+        self.last = Some(content);
+        Ok(())
+    }
+
+    /// Apply messages arriving on `channel` and send local changes, polling every `interval`
+    /// until the channel closes. Must be called from within a tokio runtime.
+    pub fn attach(self, channel: Arc<RTCDataChannel>, interval: Duration) {
+        let sync = Arc::new(Mutex::new(self));
+        let receiver = sync.clone();
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let receiver = receiver.clone();
+            Box::pin(async move {
+                if !message.is_string {
+                    return;
+                }
+                let text = String::from_utf8_lossy(&message.data).into_owned();
+                // Backends may run external commands.
+                let applied =
+                    tokio::task::spawn_blocking(move || receiver.lock().unwrap().apply(&text))
+                        .await;
+                match applied {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("Error applying clipboard {}", e),
+                    Err(e) => eprintln!("Clipboard apply panicked {}", e),
+                }
+            })
+        }));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match channel.ready_state() {
+                    RTCDataChannelState::Open => {}
+                    RTCDataChannelState::Closing | RTCDataChannelState::Closed => break,
+                    _ => continue,
+                }
+                let sync = sync.clone();
+                // Backends may run external commands.
+                let polled = tokio::task::spawn_blocking(move || sync.lock().unwrap().poll()).await;
+                match polled {
+                    Ok(Ok(Some(message))) => {
+                        if let Err(e) = channel.send_text(message.to_json()).await {
+                            eprintln!("Error sending clipboard {}", e);
+                        }
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(e)) => eprintln!("Error reading clipboard {}", e),
+                    Err(e) => eprintln!("Clipboard poll panicked {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> ClipboardConfig {
+        ClipboardConfig {
+            send: true,
+            receive: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn changes_are_sent_once_and_not_echoed() {
+        let local = MemoryClipboard::default();
+        let mut sync = ClipboardSync::new(enabled(), Box::new(local.clone()));
+        assert_eq!(sync.poll().unwrap(), None);
+
+        local.set(ClipboardContent::Text("hello".to_string()));
+        let message = sync.poll().unwrap().unwrap();
+        assert_eq!(message.to_json(), r#"{"type":"text","text":"hello"}"#);
+        assert_eq!(sync.poll().unwrap(), None);
+
+        sync.apply(r#"{"type":"text","text":"from admin"}"#)
+            .unwrap();
+        assert_eq!(
+            local.get(),
+            Some(ClipboardContent::Text("from admin".to_string()))
+        );
+        assert_eq!(sync.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn limits_and_opt_in_are_enforced() {
+        let local = MemoryClipboard::default();
+        let mut sync = ClipboardSync::new(ClipboardConfig::default(), Box::new(local.clone()));
+        local.set(ClipboardContent::Text("secret".to_string()));
+        assert_eq!(sync.poll().unwrap(), None);
+        assert!(sync.apply(r#"{"type":"text","text":"x"}"#).is_err());
+
+        let mut sync = ClipboardSync::new(enabled(), Box::new(local.clone()));
+        local.set(ClipboardContent::Image(vec![1, 2, 3]));
+        assert_eq!(sync.poll().unwrap(), None);
+        let image = ClipboardMessage::from(&ClipboardContent::Image(vec![1, 2, 3]));
+        assert!(sync.apply(&image.to_json()).is_err());
+        let huge = ClipboardMessage::Text {
+            text: "a".repeat(64 * 1024),
+        };
+        assert!(sync.apply(&huge.to_json()).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::{bail, Result};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::{auth::Role, RTC_CONSENT};
+
+/// A viewer asking to see the screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewerRequest {
+    pub client_id: String,
+    /// Role the viewer authenticated with, when authentication is enabled.
+    pub role: Option<Role>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentDecision {
+    Accept,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentOutcome {
+    Accepted,
+    Denied,
+    /// Nobody answered the prompt in time, which counts as a denial.
+    TimedOut,
+}
+
+/// Why a viewer stopped watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The local user took the permission back.
+    Revoked,
+    /// The viewer left or another viewer was let in.
+    Ended,
+}
+
+/// Asks the person at the machine whether a viewer may watch.
+///
+/// Prompts run on a blocking thread, so a dialog can simply wait for the answer.
+pub trait ConsentPrompt: Send + Sync {
+    fn ask(&self, request: &ViewerRequest) -> ConsentDecision;
+}
+
+impl<F> ConsentPrompt for F
+where
+    F: Fn(&ViewerRequest) -> ConsentDecision + Send + Sync,
+{
+    fn ask(&self, request: &ViewerRequest) -> ConsentDecision {
+        self(request)
+    }
+}
+
+/// Asks with a `zenity` question dialog.
+#[derive(Debug, Default)]
+pub struct DialogPrompt;
+
+impl ConsentPrompt for DialogPrompt {
+    fn ask(&self, request: &ViewerRequest) -> ConsentDecision {
+        let text = format!("{} wants to see your screen. Allow?", request.client_id);
+        match Command::new("zenity")
+            .args(["--question", "--title=Screen sharing", "--text", &text])
+            .status()
+        {
+            Ok(status) if status.success() => ConsentDecision::Accept,
+            Ok(_) => ConsentDecision::Deny,
+            Err(e) => {
+                eprintln!("Error showing consent dialog {}", e);
+                ConsentDecision::Deny
+            }
+        }
+    }
+}
+
+/// Shows that the screen is being watched, for as long as it is.
+pub trait WatchIndicator: Send + Sync {
+    fn watching(&self, viewer: &ViewerRequest);
+
+    fn stopped(&self, viewer: &ViewerRequest, end: SessionEnd);
+}
+
+/// Announces watching with `notify-send` desktop notifications.
+#[derive(Debug, Default)]
+pub struct NotificationIndicator;
+
+impl NotificationIndicator {
+    fn notify(&self, summary: &str, body: &str) {
+        if let Err(e) = Command::new("notify-send").args([summary, body]).status() {
+            eprintln!("Error showing notification {}", e);
+        }
+    }
+}
+
+impl WatchIndicator for NotificationIndicator {
+    fn watching(&self, viewer: &ViewerRequest) {
+        println!("{} is watching the screen", viewer.client_id);
+        self.notify(
+            "Your screen is being shared",
+            &format!("{} is watching", viewer.client_id),
+        );
+    }
+
+    fn stopped(&self, viewer: &ViewerRequest, end: SessionEnd) {
+        println!("{} stopped watching: {:?}", viewer.client_id, end);
+        self.notify(
+            "Screen sharing stopped",
+            &format!("{} is no longer watching", viewer.client_id),
+        );
+    }
+}
+
+/// Lets viewers in only with the local user's consent, and lets the user take it back.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct ConsentGate {
+    prompt: Arc<dyn ConsentPrompt>,
+    indicator: Arc<dyn WatchIndicator>,
+    timeout: Duration,
+    active: Arc<watch::Sender<Option<ViewerRequest>>>,
+}
+
+impl ConsentGate {
+    pub fn new(
+        prompt: impl ConsentPrompt + 'static,
+        indicator: impl WatchIndicator + 'static,
+        timeout: Duration,
+    ) -> Self {
+        ConsentGate {
+            prompt: Arc::new(prompt),
+            indicator: Arc::new(indicator),
+            timeout,
+            active: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Ask whether `request` may watch, giving up after the timeout. An accepted viewer
+    /// replaces the one watching before.
+    pub async fn request(&self, request: ViewerRequest) -> ConsentOutcome {
+        let prompt = self.prompt.clone();
+        let asked = request.clone();
+        let answer = tokio::task::spawn_blocking(move || prompt.ask(&asked));
+        let outcome = match tokio::time::timeout(self.timeout, answer).await {
+            Ok(Ok(ConsentDecision::Accept)) => ConsentOutcome::Accepted,
+            Ok(Ok(ConsentDecision::Deny)) => ConsentOutcome::Denied,
+            Ok(Err(e)) => {
+                eprintln!("Consent prompt failed {}", e);
+                ConsentOutcome::Denied
+            }
+            Err(_) => ConsentOutcome::TimedOut,
+        };
+        println!("Consent for {}: {:?}", request.client_id, outcome);
+        if outcome == ConsentOutcome::Accepted {
+            self.finish(SessionEnd::Ended);
+            self.indicator.watching(&request);
+            self.active.send_replace(Some(request));
+        }
+        outcome
+    }
+
+    /// The viewer currently let in.
+    pub fn active(&self) -> Option<ViewerRequest> {
+        self.active.borrow().clone()
+    }
+
+    /// Follow who is let in; `None` once the session is revoked or ends.
+    pub fn subscribe(&self) -> watch::Receiver<Option<ViewerRequest>> {
+        self.active.subscribe()
+    }
+
+    /// Take the permission of the active viewer back; returns who it was.
+    pub fn revoke(&self) -> Option<ViewerRequest> {
+        self.finish(SessionEnd::Revoked)
+    }
+
+    /// The active viewer left.
+    pub fn end(&self) -> Option<ViewerRequest> {
+        self.finish(SessionEnd::Ended)
+    }
+
+    fn finish(&self, end: SessionEnd) -> Option<ViewerRequest> {
+        let viewer = self.active.send_replace(None)?;
+        self.indicator.stopped(&viewer, end);
+        Some(viewer)
+    }
+}
+
+/// Whether a viewer may currently be sent the screen. Without [`RTC_CONSENT`] sharing
+/// needs no consent, as before consent existed.
+pub fn sharing_allowed() -> bool {
+    RTC_CONSENT.get().is_none_or(|gate| gate.active().is_some())
+}
+
+/// Fails unless a viewer has been let in through [`RTC_CONSENT`], if it is set.
+pub fn ensure_sharing_allowed() -> Result<()> {
+    if !sharing_allowed() {
+        bail!("No viewer has been given consent to watch");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default, Clone)]
+    struct RecordingIndicator(Arc<Mutex<Vec<String>>>);
+
+    impl WatchIndicator for RecordingIndicator {
+        fn watching(&self, viewer: &ViewerRequest) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("+{}", viewer.client_id));
+        }
+
+        fn stopped(&self, viewer: &ViewerRequest, end: SessionEnd) {
+            let line = format!("-{} {:?}", viewer.client_id, end);
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    fn viewer(client_id: &str) -> ViewerRequest {
+        ViewerRequest {
+            client_id: client_id.to_string(),
+            role: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn prompt_decides_and_silence_times_out() {
+        let indicator = RecordingIndicator::default();
+        let prompt = |request: &ViewerRequest| match request.client_id.as_str() {
+            "friend" => ConsentDecision::Accept,
+            "away" => {
+                std::thread::sleep(Duration::from_millis(300));
+                ConsentDecision::Accept
+            }
+            _ => ConsentDecision::Deny,
+        };
+        let gate = ConsentGate::new(prompt, indicator.clone(), Duration::from_millis(100));
+
+        assert_eq!(
+            gate.request(viewer("stranger")).await,
+            ConsentOutcome::Denied
+        );
+        assert_eq!(gate.request(viewer("away")).await, ConsentOutcome::TimedOut);
+        assert_eq!(gate.active(), None);
+        assert_eq!(
+            gate.request(viewer("friend")).await,
+            ConsentOutcome::Accepted
+        );
+        assert_eq!(gate.active(), Some(viewer("friend")));
+        assert_eq!(*indicator.0.lock().unwrap(), ["+friend"]);
+    }
+
+    #[tokio::test]
+    async fn revoking_ends_the_session_and_tells_subscribers() {
+        let indicator = RecordingIndicator::default();
+        let gate = ConsentGate::new(
+            |_: &ViewerRequest| ConsentDecision::Accept,
+            indicator.clone(),
+            Duration::from_secs(1),
+        );
+        let mut active = gate.subscribe();
+        gate.request(viewer("admin")).await;
+        assert!(active.has_changed().unwrap());
+        assert_eq!(*active.borrow_and_update(), Some(viewer("admin")));
+
+        assert_eq!(gate.revoke(), Some(viewer("admin")));
+        assert_eq!(*active.borrow_and_update(), None);
+        assert_eq!(gate.revoke(), None);
+        assert_eq!(*indicator.0.lock().unwrap(), ["+admin", "-admin Revoked"]);
+    }
+}
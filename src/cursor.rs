@@ -0,0 +1,337 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::frame_source::{RawFrame, BYTES_PER_PIXEL};
+
+/// Label of the data channel carrying [`CursorMessage`]s.
+pub const CURSOR_CHANNEL: &str = "cursor";
+
+/// How often an unchanged shape is sent again, for viewers whose channel opened after it
+/// was last sent.
+pub const SHAPE_RESEND_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How the mouse cursor is delivered to the viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CursorMode {
+    /// Don't capture the cursor at all.
+    Off,
+    /// Draw the cursor into every captured frame.
+    #[default]
+    Overlay,
+    /// Send position and shape as [`CursorMessage`]s so the viewer draws it locally.
+    DataChannel,
+}
+
+/// Cursor bitmap in straight-alpha RGBA, with its hotspot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorImage {
+    pub width: u32,
+    pub height: u32,
+    pub hot_x: u32,
+    pub hot_y: u32,
+    pub pixels: Vec<u8>,
+    /// Changes whenever the cursor shape changes.
+    pub serial: u32,
+}
+
+/// Current cursor position in display coordinates and its shape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CursorState {
+    pub x: i32,
+    pub y: i32,
+    pub image: Arc<CursorImage>,
+}
+
+/// Reports where the cursor is and what it looks like.
+pub trait CursorProvider {
+    /// Current cursor, or `None` when it is hidden.
+    fn cursor(&mut self) -> Result<Option<CursorState>>;
+}
+
+/// Cursor metadata sent over the [`CURSOR_CHANNEL`] data channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CursorMessage {
+    /// Hotspot position in pixels of a display of the given size.
+    #[serde(rename_all = "camelCase")]
+    Position {
+        x: i32,
+        y: i32,
+        display_width: u32,
+        display_height: u32,
+    },
+    /// New cursor shape as a base64 encoded PNG.
+    #[serde(rename_all = "camelCase")]
+    Shape {
+        serial: u32,
+        width: u32,
+        height: u32,
+        hot_x: u32,
+        hot_y: u32,
+        png: String,
+    },
+    Hidden,
+}
+
+impl CursorMessage {
+    pub fn shape(image: &CursorImage) -> Result<Self> {
+        let mut png = std::io::Cursor::new(vec![]);
+        image::write_buffer_with_format(
+            &mut png,
+            &image.pixels,
+            image.width,
+            image.height,
+            image::ColorType::Rgba8,
+            image::ImageFormat::Png,
+        )?;
+        Ok(CursorMessage::Shape {
+            serial: image.serial,
+            width: image.width,
+            height: image.height,
+            hot_x: image.hot_x,
+            hot_y: image.hot_y,
+            png: STANDARD.encode(png.into_inner()),
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// Turns successive cursor states into the [`CursorMessage`]s the viewer needs.
+///
+/// The shape and position are repeated every [`SHAPE_RESEND_INTERVAL`], since messages
+/// sent before the viewer's channel opened are lost.
+#[derive(Debug, Default)]
+pub struct CursorMessenger {
+    serial: Option<u32>,
+    shape_sent_at: Option<Instant>,
+    position: Option<(i32, i32)>,
+}
+
+impl CursorMessenger {
+    pub fn updates(
+        &mut self,
+        cursor: Option<&CursorState>,
+        display_width: u32,
+        display_height: u32,
+        now: Instant,
+    ) -> Result<Vec<CursorMessage>> {
+        let mut messages = vec![];
+        let Some(cursor) = cursor else {
+            if self.position.take().is_some() {
+                messages.push(CursorMessage::Hidden);
+            }
+            return Ok(messages);
+        };
+        let stale = self
+            .shape_sent_at
+            .is_none_or(|at| now.duration_since(at) >= SHAPE_RESEND_INTERVAL);
+        if self.serial != Some(cursor.image.serial) || stale {
+            messages.push(CursorMessage::shape(&cursor.image)?);
+            self.serial = Some(cursor.image.serial);
+            self.shape_sent_at = Some(now);
+            self.position = None;
+        }
+        if self.position != Some((cursor.x, cursor.y)) {
+            messages.push(CursorMessage::Position {
+                x: cursor.x,
+                y: cursor.y,
+                display_width,
+                display_height,
+            });
+            self.position = Some((cursor.x, cursor.y));
+        }
+        Ok(messages)
+    }
+}
+
+/// Alpha-blend `cursor` onto `frame`, clipping at the frame edges.
+pub fn composite_cursor(frame: &mut RawFrame, cursor: &CursorState) {
+    let image = &cursor.image;
+    let origin_x = cursor.x as i64 - image.hot_x as i64;
+    let origin_y = cursor.y as i64 - image.hot_y as i64;
+    for cy in 0..image.height as i64 {
+        let fy = origin_y + cy;
+        if fy < 0 || fy >= frame.height as i64 {
+            continue;
+        }
+        for cx in 0..image.width as i64 {
+            let fx = origin_x + cx;
+            if fx < 0 || fx >= frame.width as i64 {
+                continue;
+            }
+            let src = (cy * image.width as i64 + cx) as usize * BYTES_PER_PIXEL;
+            let dst = (fy * frame.width as i64 + fx) as usize * BYTES_PER_PIXEL;
+            let alpha = image.pixels[src + 3] as u32;
+            if alpha == 0 {
+                continue;
+            }
+            for c in 0..3 {
+                let s = image.pixels[src + c] as u32;
+                let d = frame.data[dst + c] as u32;
+                frame.data[dst + c] = ((s * alpha + d * (255 - alpha)) / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Cursor provider for the current platform, if there is one.
+pub fn default_cursor_provider() -> Result<Option<Box<dyn CursorProvider>>> {
+    #[cfg(target_os = "linux")]
+    {
+        Ok(Some(Box::new(x11::X11CursorProvider::connect()?)))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(None)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod x11 {
+    use anyhow::Result;
+    use std::sync::Arc;
+    use x11rb::protocol::xfixes::ConnectionExt;
+    use x11rb::rust_connection::RustConnection;
+
+    use super::{CursorImage, CursorProvider, CursorState};
+
+    /// Reads the cursor through the X11 XFixes extension.
+    pub struct X11CursorProvider {
+        conn: RustConnection,
+        image: Option<Arc<CursorImage>>,
+    }
+
+    impl X11CursorProvider {
+        pub fn connect() -> Result<Self> {
+            let (conn, _) = x11rb::connect(None)?;
+            conn.xfixes_query_version(4, 0)?.reply()?;
+            Ok(X11CursorProvider { conn, image: None })
+        }
+    }
+
+    impl CursorProvider for X11CursorProvider {
+        fn cursor(&mut self) -> Result<Option<CursorState>> {
+            let reply = self.conn.xfixes_get_cursor_image()?.reply()?;
+            if reply.width == 0 || reply.height == 0 {
+                return Ok(None);
+            }
+            let unchanged = self
+                .image
+                .as_ref()
+                .is_some_and(|image| image.serial == reply.cursor_serial);
+            if !unchanged {
+                // XFixes hands out premultiplied ARGB words; unpremultiply into RGBA bytes.
+                let mut pixels = Vec::with_capacity(reply.cursor_image.len() * 4);
+                for argb in &reply.cursor_image {
+                    let a = (argb >> 24) & 0xff;
+                    let unpremultiply =
+                        |c: u32| (c * 255).checked_div(a).map_or(0, |v| v.min(255) as u8);
+                    pixels.extend_from_slice(&[
+                        unpremultiply((argb >> 16) & 0xff),
+                        unpremultiply((argb >> 8) & 0xff),
+                        unpremultiply(argb & 0xff),
+                        a as u8,
+                    ]);
+                }
+                self.image = Some(Arc::new(CursorImage {
+                    width: reply.width as u32,
+                    height: reply.height as u32,
+                    hot_x: reply.xhot as u32,
+                    hot_y: reply.yhot as u32,
+                    pixels,
+                    serial: reply.cursor_serial,
+                }));
+            }
+            Ok(self.image.clone().map(|image| CursorState {
+                x: reply.x as i32,
+                y: reply.y as i32,
+                image,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(x: i32, y: i32) -> CursorState {
+        CursorState {
+            x,
+            y,
+            image: Arc::new(CursorImage {
+                width: 2,
+                height: 2,
+                hot_x: 1,
+                hot_y: 1,
+                pixels: [[255, 0, 0, 255], [255, 0, 0, 0], [0, 0, 255, 128], [0; 4]].concat(),
+                serial: 1,
+            }),
+        }
+    }
+
+    #[test]
+    fn composite_blends_at_hotspot() {
+        let mut frame = RawFrame::new(vec![0; 4 * 4 * BYTES_PER_PIXEL], 4, 4);
+        composite_cursor(&mut frame, &cursor(2, 2));
+        let px = |x: usize, y: usize| &frame.data[(y * 4 + x) * 4..(y * 4 + x) * 4 + 3];
+        assert_eq!(px(1, 1), &[255, 0, 0]);
+        assert_eq!(px(2, 1), &[0, 0, 0]);
+        assert_eq!(px(1, 2), &[0, 0, 128]);
+    }
+
+    #[test]
+    fn composite_clips_at_frame_edges() {
+        let mut frame = RawFrame::new(vec![0; 2 * 2 * BYTES_PER_PIXEL], 2, 2);
+        composite_cursor(&mut frame, &cursor(0, 0));
+        assert_eq!(&frame.data[..3], &[0, 0, 0]);
+        composite_cursor(&mut frame, &cursor(5, -3));
+    }
+
+    #[test]
+    fn messenger_sends_only_changes() {
+        let mut messenger = CursorMessenger::default();
+        let start = Instant::now();
+        let first = messenger.updates(Some(&cursor(3, 4)), 8, 8, start).unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(messenger
+            .updates(Some(&cursor(3, 4)), 8, 8, start)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            messenger.updates(Some(&cursor(5, 4)), 8, 8, start).unwrap(),
+            vec![CursorMessage::Position {
+                x: 5,
+                y: 4,
+                display_width: 8,
+                display_height: 8
+            }]
+        );
+        // A viewer that missed the shape gets it again.
+        let later = start + SHAPE_RESEND_INTERVAL;
+        let resent = messenger.updates(Some(&cursor(5, 4)), 8, 8, later).unwrap();
+        assert_eq!(
+            resent[0],
+            CursorMessage::shape(&cursor(5, 4).image).unwrap()
+        );
+        assert_eq!(resent.len(), 2);
+        assert_eq!(
+            messenger.updates(None, 8, 8, later).unwrap(),
+            vec![CursorMessage::Hidden]
+        );
+    }
+
+    #[test]
+    fn shape_message_round_trips() {
+        let msg = CursorMessage::shape(&cursor(0, 0).image).unwrap();
+        let json = msg.to_json();
+        assert!(json.contains("\"type\":\"shape\""));
+        assert_eq!(serde_json::from_str::<CursorMessage>(&json).unwrap(), msg);
+    }
+}
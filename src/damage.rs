@@ -0,0 +1,292 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+use crate::frame_source::BYTES_PER_PIXEL;
+
+/// Settings for frame-diff damage detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DamageConfig {
+    /// Edge length in pixels of the square tiles that are hashed and compared.
+    pub tile_size: u32,
+    /// Minimum frame rate kept up while the screen is static, so the remote
+    /// side keeps receiving frames. `0.0` disables keepalive frames.
+    pub keepalive_fps: f64,
+}
+
+impl Default for DamageConfig {
+    fn default() -> Self {
+        DamageConfig {
+            tile_size: 64,
+            keepalive_fps: 1.0,
+        }
+    }
+}
+
+/// A rectangular region of a frame that changed since the previous frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRect {
+    /// Map the rect into a frame scaled by `sx`/`sy`, growing it outwards so
+    /// that no changed pixel falls outside after rounding.
+    pub fn scale(&self, sx: f64, sy: f64) -> DirtyRect {
+        let x = (self.x as f64 * sx).floor() as u32;
+        let y = (self.y as f64 * sy).floor() as u32;
+        let right = ((self.x + self.width) as f64 * sx).ceil() as u32;
+        let bottom = ((self.y + self.height) as f64 * sy).ceil() as u32;
+        DirtyRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+/// Result of comparing a frame against the previous one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameDamage {
+    /// Nothing changed since the previous frame.
+    Unchanged,
+    /// Only the listed regions changed.
+    Partial(Vec<DirtyRect>),
+    /// The whole frame must be treated as new (first frame, resize, or every tile changed).
+    Full { width: u32, height: u32 },
+}
+
+impl FrameDamage {
+    pub fn is_unchanged(&self) -> bool {
+        matches!(self, FrameDamage::Unchanged)
+    }
+
+    /// Dirty regions of the frame; a full damage is reported as one rect covering the frame.
+    pub fn regions(&self) -> Vec<DirtyRect> {
+        match self {
+            FrameDamage::Unchanged => vec![],
+            FrameDamage::Partial(rects) => rects.clone(),
+            FrameDamage::Full { width, height } => vec![DirtyRect {
+                x: 0,
+                y: 0,
+                width: *width,
+                height: *height,
+            }],
+        }
+    }
+}
+
+/// Detects changed regions between consecutive frames by hashing fixed-size tiles.
+pub struct DamageTracker {
+    config: DamageConfig,
+    width: u32,
+    height: u32,
+    tile_hashes: Vec<u64>,
+    last_sent: Option<Instant>,
+}
+
+impl DamageTracker {
+    pub fn new(config: DamageConfig) -> Self {
+        DamageTracker {
+            config: DamageConfig {
+                tile_size: config.tile_size.max(1),
+                ..config
+            },
+            width: 0,
+            height: 0,
+            tile_hashes: vec![],
+            last_sent: None,
+        }
+    }
+
+    pub fn config(&self) -> DamageConfig {
+        self.config
+    }
+
+    /// Compare an RGBA `frame` against the previously seen one and remember it for the next call.
+    pub fn detect(&mut self, frame: &[u8], width: u32, height: u32) -> FrameDamage {
+        let tile = self.config.tile_size;
+        let cols = width.div_ceil(tile);
+        let rows = height.div_ceil(tile);
+        let hashes = hash_tiles(frame, width, height, tile);
+
+        let resized = width != self.width || height != self.height;
+        let previous = std::mem::replace(&mut self.tile_hashes, hashes);
+        self.width = width;
+        self.height = height;
+        if resized || previous.len() != self.tile_hashes.len() {
+            return FrameDamage::Full { width, height };
+        }
+
+        let mut rects = vec![];
+        let mut dirty_tiles = 0;
+        for row in 0..rows {
+            // Merge horizontally adjacent dirty tiles into a single run.
+            let mut run_start: Option<u32> = None;
+            for col in 0..=cols {
+                let idx = (row * cols + col) as usize;
+                let dirty = col < cols && previous[idx] != self.tile_hashes[idx];
+                if dirty {
+                    dirty_tiles += 1;
+                    run_start.get_or_insert(col);
+                } else if let Some(start) = run_start.take() {
+                    let x = start * tile;
+                    let y = row * tile;
+                    rects.push(DirtyRect {
+                        x,
+                        y,
+                        width: (col * tile).min(width) - x,
+                        height: ((row + 1) * tile).min(height) - y,
+                    });
+                }
+            }
+        }
+
+        if dirty_tiles == 0 {
+            FrameDamage::Unchanged
+        } else if dirty_tiles == self.tile_hashes.len() {
+            FrameDamage::Full { width, height }
+        } else {
+            FrameDamage::Partial(rects)
+        }
+    }
+
+    /// Whether a static screen should still produce a frame to honour the keepalive rate.
+    pub fn keepalive_due(&self, now: Instant) -> bool {
+        if self.config.keepalive_fps <= 0.0 {
+            return false;
+        }
+        match self.last_sent {
+            Some(last) => {
+                now.duration_since(last) >= Duration::from_secs_f64(1.0 / self.config.keepalive_fps)
+            }
+            None => true,
+        }
+    }
+
+    /// Record that a frame was handed to the pipeline at `now`.
+    pub fn mark_sent(&mut self, now: Instant) {
+        self.last_sent = Some(now);
+    }
+}
+
+fn hash_tiles(frame: &[u8], width: u32, height: u32, tile: u32) -> Vec<u64> {
+    let cols = width.div_ceil(tile);
+    let rows = height.div_ceil(tile);
+    let stride = width as usize * BYTES_PER_PIXEL;
+    let mut hashers: Vec<DefaultHasher> = (0..cols * rows).map(|_| DefaultHasher::new()).collect();
+
+    for y in 0..height {
+        let row_start = y as usize * stride;
+        let Some(line) = frame.get(row_start..row_start + stride) else {
+            break;
+        };
+        let tile_row = (y / tile) * cols;
+        for col in 0..cols {
+            let start = (col * tile) as usize * BYTES_PER_PIXEL;
+            let end = (((col + 1) * tile).min(width)) as usize * BYTES_PER_PIXEL;
+            hashers[(tile_row + col) as usize].write(&line[start..end]);
+        }
+    }
+
+    hashers.iter().map(|h| h.finish()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(width: u32, height: u32) -> Vec<u8> {
+        vec![0; (width * height) as usize * BYTES_PER_PIXEL]
+    }
+
+    fn paint(frame: &mut [u8], width: u32, x: u32, y: u32) {
+        let idx = ((y * width + x) as usize) * BYTES_PER_PIXEL;
+        frame[idx] = 0xff;
+    }
+
+    #[test]
+    fn first_frame_is_full_and_repeat_is_unchanged() {
+        let mut tracker = DamageTracker::new(DamageConfig {
+            tile_size: 16,
+            keepalive_fps: 1.0,
+        });
+        let f = frame(64, 32);
+        assert_eq!(
+            tracker.detect(&f, 64, 32),
+            FrameDamage::Full {
+                width: 64,
+                height: 32
+            }
+        );
+        assert_eq!(tracker.detect(&f, 64, 32), FrameDamage::Unchanged);
+    }
+
+    #[test]
+    fn adjacent_dirty_tiles_are_merged() {
+        let mut tracker = DamageTracker::new(DamageConfig {
+            tile_size: 16,
+            keepalive_fps: 1.0,
+        });
+        let mut f = frame(64, 32);
+        tracker.detect(&f, 64, 32);
+        paint(&mut f, 64, 17, 3);
+        paint(&mut f, 64, 40, 3);
+        paint(&mut f, 64, 63, 31);
+
+        let damage = tracker.detect(&f, 64, 32);
+        assert_eq!(
+            damage.regions(),
+            vec![
+                DirtyRect {
+                    x: 16,
+                    y: 0,
+                    width: 32,
+                    height: 16
+                },
+                DirtyRect {
+                    x: 48,
+                    y: 16,
+                    width: 16,
+                    height: 16
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn keepalive_follows_configured_rate() {
+        let mut tracker = DamageTracker::new(DamageConfig {
+            tile_size: 16,
+            keepalive_fps: 2.0,
+        });
+        let start = Instant::now();
+        assert!(tracker.keepalive_due(start));
+        tracker.mark_sent(start);
+        assert!(!tracker.keepalive_due(start + Duration::from_millis(100)));
+        assert!(tracker.keepalive_due(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn scaled_rect_covers_original_pixels() {
+        let rect = DirtyRect {
+            x: 5,
+            y: 5,
+            width: 10,
+            height: 10,
+        };
+        assert_eq!(
+            rect.scale(0.5, 0.5),
+            DirtyRect {
+                x: 2,
+                y: 2,
+                width: 6,
+                height: 6
+            }
+        );
+    }
+}
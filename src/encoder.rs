@@ -0,0 +1,192 @@
+use anyhow::Result;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use webrtc::rtcp::{
+    packet::Packet,
+    payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+    },
+};
+
+use crate::frame_bus::VideoFrame;
+use crate::rate_control::ContentHint;
+
+/// Output of a [`VideoEncoder`] for one frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedFrame {
+    pub data: Bytes,
+    /// Whether the frame decodes without any earlier frame.
+    pub keyframe: bool,
+}
+
+/// Turns frames from the capture into the payload written to the video track.
+pub trait VideoEncoder: Send {
+    /// Target bitrate in bits per second, applied from the next frame on.
+    fn set_bitrate(&mut self, bitrate: u32);
+
+    /// Make the next encoded frame a keyframe.
+    fn request_keyframe(&mut self);
+
+    /// Tune the encoder for what the screen shows, applied from the next frame on.
+    /// [`PassthroughEncoder`] has nothing to tune.
+    fn set_content_hint(&mut self, hint: ContentHint);
+
+    fn encode(&mut self, frame: &VideoFrame) -> Result<EncodedFrame>;
+}
+
+/// Writes frames as they are. Stands in until a codec is wired up.
+///
+/// It cannot hit a target bitrate: [`VideoEncoder::set_bitrate`] is only recorded, and the
+/// output adapts to the network through the frame rate and resolution alone.
+///
+/// Every frame is complete on its own, so every frame is a keyframe.
+#[derive(Debug, Default)]
+pub struct PassthroughEncoder {
+    bitrate: Option<u32>,
+}
+
+impl PassthroughEncoder {
+    pub fn bitrate(&self) -> Option<u32> {
+        self.bitrate
+    }
+}
+
+impl VideoEncoder for PassthroughEncoder {
+    fn set_bitrate(&mut self, bitrate: u32) {
+        self.bitrate = Some(bitrate);
+    }
+
+    fn request_keyframe(&mut self) {}
+
+    fn set_content_hint(&mut self, _hint: ContentHint) {}
+
+    fn encode(&mut self, frame: &VideoFrame) -> Result<EncodedFrame> {
+        Ok(EncodedFrame {
+            data: frame.data.clone(),
+            keyframe: true,
+        })
+    }
+}
+
+/// How the sender answers keyframe requests from the viewer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyframePolicy {
+    /// Requests arriving sooner than this after the last forced keyframe are ignored;
+    /// a burst of PLIs from one loss event then costs a single keyframe.
+    pub min_interval: Duration,
+}
+
+impl Default for KeyframePolicy {
+    fn default() -> Self {
+        KeyframePolicy {
+            min_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Keyframe counters of a capture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyframeStats {
+    pub pli_received: u64,
+    pub fir_received: u64,
+    /// Requests ignored because a keyframe was forced less than `min_interval` earlier.
+    pub rate_limited: u64,
+    /// Keyframes forced in answer to requests.
+    pub forced: u64,
+    /// Keyframes written to the track, forced or not; every frame with [`PassthroughEncoder`].
+    pub sent: u64,
+}
+
+/// Turns PLI and FIR packets into rate-limited keyframe requests and counts them.
+///
+/// A forced keyframe changes nothing yet: [`PassthroughEncoder`] ignores
+/// [`VideoEncoder::request_keyframe`], as all its frames are keyframes anyway. Only the
+/// counters and the immediate resend of the held frame are live until a real encoder exists.
+#[derive(Debug, Default)]
+pub struct KeyframeRequests {
+    policy: KeyframePolicy,
+    last_forced: Mutex<Option<Instant>>,
+    pli_received: AtomicU64,
+    fir_received: AtomicU64,
+    rate_limited: AtomicU64,
+    forced: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl KeyframeRequests {
+    pub fn new(policy: KeyframePolicy) -> Self {
+        KeyframeRequests {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Count the PLI and FIR packets among `packets`; returns whether to force a keyframe now.
+    pub fn on_rtcp(&self, packets: &[Box<dyn Packet + Send + Sync>], now: Instant) -> bool {
+        let mut requested = false;
+        for packet in packets {
+            let any = packet.as_any();
+            if any.downcast_ref::<PictureLossIndication>().is_some() {
+                self.pli_received.fetch_add(1, Ordering::Relaxed);
+                requested = true;
+            } else if any.downcast_ref::<FullIntraRequest>().is_some() {
+                self.fir_received.fetch_add(1, Ordering::Relaxed);
+                requested = true;
+            }
+        }
+        requested && self.allow(now)
+    }
+
+    fn allow(&self, now: Instant) -> bool {
+        let mut last_forced = self.last_forced.lock().unwrap();
+        let limited = last_forced.is_some_and(|t| now.duration_since(t) < self.policy.min_interval);
+        if limited {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        *last_forced = Some(now);
+        self.forced.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Record a keyframe written to the track.
+    pub fn record_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> KeyframeStats {
+        KeyframeStats {
+            pli_received: self.pli_received.load(Ordering::Relaxed),
+            fir_received: self.fir_received.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            forced: self.forced.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframe_requests_are_rate_limited_and_counted() {
+        let requests = KeyframeRequests::new(KeyframePolicy::default());
+        let pli =
+            || -> Box<dyn Packet + Send + Sync> { Box::new(PictureLossIndication::default()) };
+        let fir = || -> Box<dyn Packet + Send + Sync> { Box::new(FullIntraRequest::default()) };
+        let now = Instant::now();
+
+        assert!(requests.on_rtcp(&[pli(), pli()], now));
+        assert!(!requests.on_rtcp(&[fir()], now + Duration::from_millis(100)));
+        assert!(requests.on_rtcp(&[fir()], now + Duration::from_millis(600)));
+        assert!(!requests.on_rtcp(&[], now + Duration::from_secs(5)));
+
+        // Counted as forced, though the passthrough encoder has nothing to force.
+        let stats = requests.stats();
+        assert_eq!((stats.pli_received, stats.fir_received), (2, 2));
+        assert_eq!((stats.forced, stats.rate_limited), (2, 1));
+    }
+}
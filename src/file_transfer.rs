@@ -0,0 +1,632 @@
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, Notify};
+use webrtc::data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel};
+
+use crate::{
+    auth::{self, Role},
+    consent::sharing_allowed,
+};
+
+/// Label of the reliable, ordered data channel files are sent over.
+pub const FILE_TRANSFER_CHANNEL: &str = "file-transfer";
+
+/// Payload bytes per chunk; 16 KiB is what every data channel implementation accepts.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+/// Sending pauses while more than this is queued on the channel...
+const HIGH_WATER_MARK: usize = 1024 * 1024;
+/// ...and resumes once the queue drained below this.
+const LOW_WATER_MARK: usize = 256 * 1024;
+/// Transfer id and offset in front of every chunk.
+const CHUNK_HEADER_LEN: usize = 12;
+
+/// What is about to be sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub name: String,
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the whole file.
+    pub sha256: String,
+}
+
+impl Manifest {
+    pub fn for_file(path: &Path) -> Result<Self> {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            bail!("{} has no file name", path.display());
+        };
+        let mut file = File::open(path)?;
+        Ok(Manifest {
+            name: name.to_string(),
+            size: file.metadata()?.len(),
+            sha256: sha256_hex(&mut file)?,
+        })
+    }
+}
+
+fn sha256_hex(reader: &mut impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Control messages, sent as text; chunks go as binary messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TransferMessage {
+    /// Sender announces a file under an id of its choosing.
+    Offer { id: u32, manifest: Manifest },
+    /// Receiver asks for the file from `offset`, the bytes it kept from an earlier attempt.
+    Accept { id: u32, offset: u64 },
+    /// Receiver got the whole file and its hash matched.
+    Complete { id: u32 },
+    /// Receiver declined, failed or cancelled.
+    Reject { id: u32, reason: String },
+    /// Sender cancelled.
+    Cancel { id: u32, reason: String },
+}
+
+impl TransferMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+}
+
+/// A binary chunk message: transfer id and offset, big endian, then the bytes.
+pub fn encode_chunk(id: u32, offset: u64, data: &[u8]) -> Bytes {
+    let mut chunk = BytesMut::with_capacity(CHUNK_HEADER_LEN + data.len());
+    chunk.put_u32(id);
+    chunk.put_u64(offset);
+    chunk.put_slice(data);
+    chunk.freeze()
+}
+
+pub fn decode_chunk(chunk: &[u8]) -> Result<(u32, u64, &[u8])> {
+    if chunk.len() < CHUNK_HEADER_LEN {
+        bail!("Chunk of {} bytes has no header", chunk.len());
+    }
+    let id = u32::from_be_bytes(chunk[..4].try_into()?);
+    let offset = u64::from_be_bytes(chunk[4..CHUNK_HEADER_LEN].try_into()?);
+    Ok((id, offset, &chunk[CHUNK_HEADER_LEN..]))
+}
+
+/// A file being received into a download directory.
+///
+/// Bytes land in `<sha256>.part`, which outlives the connection: offering the same file
+/// again, after a reconnection or a restart, picks up where the previous attempt stopped.
+#[derive(Debug)]
+pub struct IncomingFile {
+    manifest: Manifest,
+    dir: PathBuf,
+    part: PathBuf,
+    file: File,
+    received: u64,
+}
+
+impl IncomingFile {
+    pub fn open(dir: &Path, manifest: Manifest) -> Result<Self> {
+        let valid_name = Path::new(&manifest.name).file_name() == Some(manifest.name.as_ref());
+        if !valid_name {
+            bail!("Invalid file name {:?}", manifest.name);
+        }
+        let valid_hash = manifest.sha256.len() == 64
+            && manifest
+                .sha256
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        if !valid_hash {
+            bail!("Invalid SHA-256 {:?}", manifest.sha256);
+        }
+        fs::create_dir_all(dir)?;
+        let part = dir.join(format!("{}.part", manifest.sha256));
+        let file = OpenOptions::new().create(true).append(true).open(&part)?;
+        let mut received = file.metadata()?.len();
+        if received > manifest.size {
+            file.set_len(0)?;
+            received = 0;
+        }
+        Ok(IncomingFile {
+            manifest,
+            dir: dir.to_path_buf(),
+            part,
+            file,
+            received,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Bytes received so far, including those of earlier attempts.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.manifest.size
+    }
+
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset != self.received {
+            bail!("Chunk at {} while expecting {}", offset, self.received);
+        }
+        if self.received + data.len() as u64 > self.manifest.size {
+            bail!("Chunk past the end of {}", self.manifest.name);
+        }
+        self.file.write_all(data)?;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Check the hash and move the file to its name in the download directory; the partial
+    /// file is deleted when the hash does not match.
+    pub fn finish(self) -> Result<PathBuf> {
+        drop(self.file);
+        let sha256 = sha256_hex(&mut File::open(&self.part)?)?;
+        if sha256 != self.manifest.sha256 {
+            fs::remove_file(&self.part)?;
+            bail!("SHA-256 mismatch for {}", self.manifest.name);
+        }
+        let mut path = self.dir.join(&self.manifest.name);
+        let mut copy = 1;
+        while path.exists() {
+            path = self.dir.join(format!("{} ({})", self.manifest.name, copy));
+            copy += 1;
+        }
+        fs::rename(&self.part, &path)?;
+        Ok(path)
+    }
+
+    /// Drop the bytes received so far.
+    pub fn discard(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.part)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+/// What happens to transfers, in the order it happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransferEvent {
+    /// The peer offered a file; it is being received into the download directory.
+    Offered { id: u32, manifest: Manifest },
+    Progress {
+        id: u32,
+        direction: Direction,
+        transferred: u64,
+        total: u64,
+    },
+    /// The file arrived intact; `path` is where it was read from or saved to.
+    Completed {
+        id: u32,
+        direction: Direction,
+        path: PathBuf,
+    },
+    Cancelled {
+        id: u32,
+        direction: Direction,
+        reason: String,
+    },
+}
+
+struct Outgoing {
+    path: PathBuf,
+    manifest: Manifest,
+    cancelled: Arc<AtomicBool>,
+}
+
+struct Inner {
+    channel: Arc<RTCDataChannel>,
+    download_dir: PathBuf,
+    next_id: AtomicU32,
+    outgoing: Mutex<HashMap<u32, Outgoing>>,
+    incoming: Mutex<HashMap<u32, IncomingFile>>,
+    events: mpsc::UnboundedSender<TransferEvent>,
+    buffered_low: Notify,
+}
+
+/// Sends and receives files over one [`FILE_TRANSFER_CHANNEL`] data channel.
+///
+/// After a reconnection, attach to the new channel and send the file again: the receiver
+/// answers with the offset it already has, so only the rest goes over the wire.
+/// Clones share the same transfers.
+#[derive(Clone)]
+pub struct FileTransfer {
+    inner: Arc<Inner>,
+}
+
+impl FileTransfer {
+    /// Handle transfers on `channel`, saving received files into `download_dir`.
+    pub async fn attach(
+        channel: Arc<RTCDataChannel>,
+        download_dir: PathBuf,
+    ) -> (FileTransfer, mpsc::UnboundedReceiver<TransferEvent>) {
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let inner = Arc::new(Inner {
+            channel: channel.clone(),
+            download_dir,
+            next_id: AtomicU32::new(1),
+            outgoing: Mutex::default(),
+            incoming: Mutex::default(),
+            events,
+            buffered_low: Notify::new(),
+        });
+
+        channel
+            .set_buffered_amount_low_threshold(LOW_WATER_MARK)
+            .await;
+        let weak = Arc::downgrade(&inner);
+        channel
+            .on_buffered_amount_low(Box::new(move || {
+                if let Some(inner) = weak.upgrade() {
+                    inner.buffered_low.notify_waiters();
+                }
+                Box::pin(async {})
+            }))
+            .await;
+        let weak = Arc::downgrade(&inner);
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let inner = weak.upgrade();
+            Box::pin(async move {
+                let Some(inner) = inner else {
+                    return;
+                };
+                if let Err(e) = FileTransfer::handle(&inner, message).await {
+                    eprintln!("Error handling file transfer message {}", e);
+                }
+            })
+        }));
+        (FileTransfer { inner }, events_rx)
+    }
+
+    /// Offer the file at `path` to the peer; it is sent once the peer accepts.
+    pub async fn send_file(&self, path: &Path) -> Result<u32> {
+        let path = path.to_path_buf();
+        let manifest = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || Manifest::for_file(&path)).await??
+        };
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.outgoing.lock().unwrap().insert(
+            id,
+            Outgoing {
+                path,
+                manifest: manifest.clone(),
+                cancelled: Arc::default(),
+            },
+        );
+        send_message(&self.inner, &TransferMessage::Offer { id, manifest }).await?;
+        Ok(id)
+    }
+
+    /// Stop a transfer in either direction and tell the peer. A cancelled download drops
+    /// what was received, so it cannot be resumed.
+    pub async fn cancel(&self, direction: Direction, id: u32) -> Result<()> {
+        let reason = "Cancelled".to_string();
+        let message = match direction {
+            Direction::Sending => {
+                let Some(outgoing) = self.inner.outgoing.lock().unwrap().remove(&id) else {
+                    bail!("No file is being sent as {}", id);
+                };
+                outgoing.cancelled.store(true, Ordering::Relaxed);
+                TransferMessage::Cancel {
+                    id,
+                    reason: reason.clone(),
+                }
+            }
+            Direction::Receiving => {
+                let Some(incoming) = self.inner.incoming.lock().unwrap().remove(&id) else {
+                    bail!("No file is being received as {}", id);
+                };
+                incoming.discard()?;
+                TransferMessage::Reject {
+                    id,
+                    reason: reason.clone(),
+                }
+            }
+        };
+        let _ = self.inner.events.send(TransferEvent::Cancelled {
+            id,
+            direction,
+            reason,
+        });
+        send_message(&self.inner, &message).await
+    }
+
+    async fn handle(inner: &Arc<Inner>, message: DataChannelMessage) -> Result<()> {
+        if !message.is_string {
+            return FileTransfer::receive_chunk(inner, &message.data).await;
+        }
+        let message: TransferMessage = serde_json::from_slice(&message.data)?;
+        match message {
+            TransferMessage::Offer { id, manifest } => {
+                if !auth::permits(Role::Control) {
+                    let reason = "Session is not authorized to send files".to_string();
+                    return send_message(inner, &TransferMessage::Reject { id, reason }).await;
+                }
+                let dir = inner.download_dir.clone();
+                let opened =
+                    tokio::task::spawn_blocking(move || IncomingFile::open(&dir, manifest)).await?;
+                let incoming = match opened {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        let reason = e.to_string();
+                        return send_message(inner, &TransferMessage::Reject { id, reason }).await;
+                    }
+                };
+                let offset = incoming.received();
+                let _ = inner.events.send(TransferEvent::Offered {
+                    id,
+                    manifest: incoming.manifest().clone(),
+                });
+                let complete = incoming.is_complete();
+                inner.incoming.lock().unwrap().insert(id, incoming);
+                send_message(inner, &TransferMessage::Accept { id, offset }).await?;
+                if complete {
+                    FileTransfer::finish_incoming(inner, id).await?;
+                }
+            }
+            TransferMessage::Accept { id, offset } => {
+                let outgoing = inner
+                    .outgoing
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .map(|o| (o.path.clone(), o.manifest.size, o.cancelled.clone()));
+                let Some((path, size, cancelled)) = outgoing else {
+                    bail!("Peer accepted unknown transfer {}", id);
+                };
+                let inner = inner.clone();
+                tokio::spawn(async move {
+                    let sent = stream_file(&inner, id, &path, offset, size, &cancelled).await;
+                    if let Err(e) = sent {
+                        inner.outgoing.lock().unwrap().remove(&id);
+                        let _ = inner.events.send(TransferEvent::Cancelled {
+                            id,
+                            direction: Direction::Sending,
+                            reason: e.to_string(),
+                        });
+                    }
+                });
+            }
+            TransferMessage::Complete { id } => {
+                if let Some(outgoing) = inner.outgoing.lock().unwrap().remove(&id) {
+                    let _ = inner.events.send(TransferEvent::Completed {
+                        id,
+                        direction: Direction::Sending,
+                        path: outgoing.path,
+                    });
+                }
+            }
+            TransferMessage::Reject { id, reason } => {
+                if let Some(outgoing) = inner.outgoing.lock().unwrap().remove(&id) {
+                    outgoing.cancelled.store(true, Ordering::Relaxed);
+                    let _ = inner.events.send(TransferEvent::Cancelled {
+                        id,
+                        direction: Direction::Sending,
+                        reason,
+                    });
+                }
+            }
+            TransferMessage::Cancel { id, reason } => {
+                let incoming = inner.incoming.lock().unwrap().remove(&id);
+                if let Some(incoming) = incoming {
+                    incoming.discard()?;
+                    let _ = inner.events.send(TransferEvent::Cancelled {
+                        id,
+                        direction: Direction::Receiving,
+                        reason,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_chunk(inner: &Arc<Inner>, chunk: &[u8]) -> Result<()> {
+        let (id, offset, data) = decode_chunk(chunk)?;
+        let written = {
+            let mut incoming = inner.incoming.lock().unwrap();
+            let Some(file) = incoming.get_mut(&id) else {
+                // Chunks still in flight after a cancel.
+                return Ok(());
+            };
+            file.write_chunk(offset, data)
+                .map(|_| (file.received(), file.manifest().size))
+        };
+        let (received, size) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                // The partial file keeps what arrived in order, for a later resume.
+                inner.incoming.lock().unwrap().remove(&id);
+                let reason = e.to_string();
+                let _ = inner.events.send(TransferEvent::Cancelled {
+                    id,
+                    direction: Direction::Receiving,
+                    reason: reason.clone(),
+                });
+                return send_message(inner, &TransferMessage::Reject { id, reason }).await;
+            }
+        };
+        let _ = inner.events.send(TransferEvent::Progress {
+            id,
+            direction: Direction::Receiving,
+            transferred: received,
+            total: size,
+        });
+        if received == size {
+            FileTransfer::finish_incoming(inner, id).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish_incoming(inner: &Arc<Inner>, id: u32) -> Result<()> {
+        let Some(file) = inner.incoming.lock().unwrap().remove(&id) else {
+            return Ok(());
+        };
+        let (message, event) = match tokio::task::spawn_blocking(move || file.finish()).await? {
+            Ok(path) => (
+                TransferMessage::Complete { id },
+                TransferEvent::Completed {
+                    id,
+                    direction: Direction::Receiving,
+                    path,
+                },
+            ),
+            Err(e) => (
+                TransferMessage::Reject {
+                    id,
+                    reason: e.to_string(),
+                },
+                TransferEvent::Cancelled {
+                    id,
+                    direction: Direction::Receiving,
+                    reason: e.to_string(),
+                },
+            ),
+        };
+        let _ = inner.events.send(event);
+        send_message(inner, &message).await
+    }
+}
+
+async fn send_message(inner: &Inner, message: &TransferMessage) -> Result<()> {
+    if !sharing_allowed() {
+        bail!("Sharing with the viewer was revoked");
+    }
+    inner.channel.send_text(message.to_json()).await?;
+    Ok(())
+}
+
+/// Send `path` from `offset` in chunks, pausing whenever the channel has too much queued.
+async fn stream_file(
+    inner: &Inner,
+    id: u32,
+    path: &Path,
+    offset: u64,
+    size: u64,
+    cancelled: &AtomicBool,
+) -> Result<()> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut sent = offset;
+    while sent < size {
+        while inner.channel.buffered_amount().await > HIGH_WATER_MARK {
+            // The low event can fire between the check and the wait, so never wait long.
+            let _ = tokio::time::timeout(Duration::from_millis(100), inner.buffered_low.notified())
+                .await;
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if !sharing_allowed() {
+            bail!("Sharing with the viewer was revoked");
+        }
+        let wanted = CHUNK_SIZE.min((size - sent) as usize);
+        let n = file.read(&mut buffer[..wanted]).await?;
+        if n == 0 {
+            bail!("{} ended at {} of {} bytes", path.display(), sent, size);
+        }
+        inner
+            .channel
+            .send(&encode_chunk(id, sent, &buffer[..n]))
+            .await?;
+        sent += n as u64;
+        let _ = inner.events.send(TransferEvent::Progress {
+            id,
+            direction: Direction::Sending,
+            transferred: sent,
+            total: size,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("webrtc_client_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let chunk = encode_chunk(7, 1 << 40, b"abc");
+        assert_eq!(chunk.len(), CHUNK_HEADER_LEN + 3);
+        assert_eq!(decode_chunk(&chunk).unwrap(), (7, 1 << 40, &b"abc"[..]));
+        assert!(decode_chunk(&[0; 4]).is_err());
+    }
+
+    #[test]
+    fn interrupted_download_resumes_and_is_verified() {
+        let dir = temp_path("downloads");
+        let source_dir = temp_path("uploads");
+        fs::create_dir_all(&source_dir).unwrap();
+        let source = source_dir.join("report.txt");
+        let data: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        let manifest = Manifest::for_file(&source).unwrap();
+        assert_eq!(
+            (manifest.name.as_str(), manifest.size),
+            ("report.txt", 40_000)
+        );
+
+        let mut first = IncomingFile::open(&dir, manifest.clone()).unwrap();
+        first.write_chunk(0, &data[..CHUNK_SIZE]).unwrap();
+        assert!(first.write_chunk(0, &data[..10]).is_err());
+        drop(first);
+
+        // Offered again after a reconnection.
+        let mut second = IncomingFile::open(&dir, manifest.clone()).unwrap();
+        assert_eq!(second.received(), CHUNK_SIZE as u64);
+        second
+            .write_chunk(CHUNK_SIZE as u64, &data[CHUNK_SIZE..])
+            .unwrap();
+        assert!(second.is_complete());
+        let path = second.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        let mut tampered = IncomingFile::open(&dir, manifest).unwrap();
+        tampered.write_chunk(0, &vec![0; 40_000]).unwrap();
+        assert!(tampered.finish().is_err());
+
+        let escape = Manifest {
+            name: "../evil".to_string(),
+            size: 1,
+            sha256: "0".repeat(64),
+        };
+        assert!(IncomingFile::open(&dir, escape).is_err());
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(source_dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tokio::sync::watch;
+
+use crate::damage::DirtyRect;
+
+/// Pixel layout or codec of [`VideoFrame::data`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FrameFormat {
+    /// Uncompressed, 4 bytes per pixel.
+    Rgba,
+}
+
+/// A frame travelling through a [`FrameBus`]. Shared as `Arc<VideoFrame>`, so
+/// subscribers never copy the pixel data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoFrame {
+    pub data: Bytes,
+    pub width: u32,
+    pub height: u32,
+    pub format: FrameFormat,
+    /// Monotonic capture time, used to derive sample durations.
+    pub captured_at: Instant,
+    pub keyframe: bool,
+    /// Regions that changed since the previous frame, in the coordinates of `data`.
+    pub dirty: Vec<DirtyRect>,
+    /// Position in the bus, assigned on publish.
+    pub sequence: u64,
+}
+
+impl VideoFrame {
+    pub fn new(
+        data: impl Into<Bytes>,
+        width: u32,
+        height: u32,
+        format: FrameFormat,
+        captured_at: Instant,
+    ) -> Self {
+        VideoFrame {
+            data: data.into(),
+            width,
+            height,
+            format,
+            captured_at,
+            keyframe: false,
+            dirty: vec![],
+            sequence: 0,
+        }
+    }
+}
+
+/// Frame counters of one [`FrameSubscriber`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriberStats {
+    pub name: String,
+    /// Frames handed to the subscriber.
+    pub received: u64,
+    /// Frames replaced by a newer one before the subscriber got to them.
+    pub dropped: u64,
+}
+
+#[derive(Debug)]
+struct StatsCell {
+    name: String,
+    received: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl StatsCell {
+    fn snapshot(&self) -> SubscriberStats {
+        SubscriberStats {
+            name: self.name.clone(),
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    tx: watch::Sender<Option<Arc<VideoFrame>>>,
+    next_sequence: AtomicU64,
+    subscribers: Mutex<Vec<Weak<StatsCell>>>,
+}
+
+/// Distributes frames to any number of subscribers with latest-frame-wins semantics:
+/// publishing never blocks, and a slow subscriber skips straight to the newest frame.
+///
+/// Clones share the same bus. Once every clone is dropped, subscribers drain and end.
+#[derive(Debug, Clone)]
+pub struct FrameBus {
+    inner: Arc<Inner>,
+}
+
+impl Default for FrameBus {
+    fn default() -> Self {
+        FrameBus::new()
+    }
+}
+
+impl FrameBus {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(None);
+        FrameBus {
+            inner: Arc::new(Inner {
+                tx,
+                next_sequence: AtomicU64::new(0),
+                subscribers: Mutex::new(vec![]),
+            }),
+        }
+    }
+
+    /// Publish `frame`, replacing any frame subscribers have not picked up yet.
+    pub fn publish(&self, mut frame: VideoFrame) -> Arc<VideoFrame> {
+        frame.sequence = self.inner.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let frame = Arc::new(frame);
+        self.inner.tx.send_replace(Some(frame.clone()));
+        frame
+    }
+
+    /// Subscribe starting with the most recently published frame, if there is one, so a
+    /// subscriber that attaches after the first publish still starts with a frame.
+    pub fn subscribe(&self, name: &str) -> FrameSubscriber {
+        let stats = Arc::new(StatsCell {
+            name: name.to_string(),
+            received: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        subscribers.retain(|s| s.strong_count() > 0);
+        subscribers.push(Arc::downgrade(&stats));
+        let mut rx = self.inner.tx.subscribe();
+        let retained = rx.borrow().as_ref().map(|frame| frame.sequence);
+        if retained.is_some() {
+            rx.mark_changed();
+        }
+        FrameSubscriber {
+            rx,
+            next_sequence: retained.unwrap_or(0),
+            stats,
+        }
+    }
+
+    /// Counters of every live subscriber.
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|s| s.upgrade().map(|s| s.snapshot()))
+            .collect()
+    }
+}
+
+/// Receiving end of a [`FrameBus`].
+#[derive(Debug)]
+pub struct FrameSubscriber {
+    rx: watch::Receiver<Option<Arc<VideoFrame>>>,
+    next_sequence: u64,
+    stats: Arc<StatsCell>,
+}
+
+impl FrameSubscriber {
+    /// Wait for a frame newer than the last one received; `None` once the bus is gone.
+    pub async fn recv(&mut self) -> Option<Arc<VideoFrame>> {
+        loop {
+            self.rx.changed().await.ok()?;
+            if let Some(frame) = self.take() {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Newest unseen frame, if one was published since the last call.
+    pub fn try_recv(&mut self) -> Option<Arc<VideoFrame>> {
+        if !self.rx.has_changed().unwrap_or(false) {
+            return None;
+        }
+        self.take()
+    }
+
+    pub fn stats(&self) -> SubscriberStats {
+        self.stats.snapshot()
+    }
+
+    fn take(&mut self) -> Option<Arc<VideoFrame>> {
+        let frame = self.rx.borrow_and_update().clone()?;
+        if frame.sequence < self.next_sequence {
+            return None;
+        }
+        self.stats
+            .dropped
+            .fetch_add(frame.sequence - self.next_sequence, Ordering::Relaxed);
+        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        self.next_sequence = frame.sequence + 1;
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(byte: u8) -> VideoFrame {
+        VideoFrame::new(vec![byte; 4], 1, 1, FrameFormat::Rgba, Instant::now())
+    }
+
+    #[tokio::test]
+    async fn slow_subscriber_gets_latest_frame_and_counts_drops() {
+        let bus = FrameBus::new();
+        let mut fast = bus.subscribe("fast");
+        let mut slow = bus.subscribe("slow");
+
+        for i in 0..3 {
+            bus.publish(frame(i));
+            assert_eq!(fast.recv().await.unwrap().data[0], i);
+        }
+        let latest = slow.recv().await.unwrap();
+        assert_eq!(latest.data[0], 2);
+
+        let stats = bus.subscriber_stats();
+        assert_eq!(stats[0].received, 3);
+        assert_eq!(stats[0].dropped, 0);
+        assert_eq!(stats[1].received, 1);
+        assert_eq!(stats[1].dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn frames_are_shared_not_copied() {
+        let bus = FrameBus::new();
+        let mut a = bus.subscribe("a");
+        let mut b = bus.subscribe("b");
+        let published = bus.publish(frame(7));
+        let (fa, fb) = (a.recv().await.unwrap(), b.recv().await.unwrap());
+        assert!(Arc::ptr_eq(&fa, &published) && Arc::ptr_eq(&fb, &published));
+    }
+
+    #[tokio::test]
+    async fn late_subscriber_starts_with_retained_frame() {
+        let bus = FrameBus::new();
+        bus.publish(frame(0));
+        bus.publish(frame(1));
+
+        let mut late = bus.subscribe("late");
+        assert_eq!(late.recv().await.unwrap().data[0], 1);
+        assert_eq!(late.stats().dropped, 0);
+        bus.publish(frame(2));
+        assert_eq!(late.recv().await.unwrap().data[0], 2);
+    }
+
+    #[tokio::test]
+    async fn subscribers_end_when_bus_is_dropped() {
+        let bus = FrameBus::new();
+        let mut sub = bus.subscribe("writer");
+        assert!(sub.try_recv().is_none());
+        drop(bus);
+        assert!(sub.recv().await.is_none());
+    }
+}
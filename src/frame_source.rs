@@ -0,0 +1,389 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Bytes per pixel of every [`RawFrame`].
+pub const BYTES_PER_PIXEL: usize = 4;
+
+/// One uncompressed RGBA frame as produced by a [`FrameSource`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// Monotonic time at which the frame was captured.
+    pub captured_at: Instant,
+}
+
+impl RawFrame {
+    /// Create a frame captured now.
+    pub fn new(data: Vec<u8>, width: u32, height: u32) -> Self {
+        RawFrame {
+            data,
+            width,
+            height,
+            captured_at: Instant::now(),
+        }
+    }
+}
+
+/// Something the capture loop can pull frames from.
+///
+/// Sources are opened on the capture thread, so they do not need to be `Send`.
+pub trait FrameSource {
+    /// Block until the next frame is available. `Ok(None)` ends the stream.
+    fn next_frame(&mut self) -> Result<Option<RawFrame>>;
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for Box<S> {
+    fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        (**self).next_frame()
+    }
+}
+
+/// Synthetic source drawing color bars, a moving box and a frame counter.
+pub struct TestPatternSource {
+    width: u32,
+    height: u32,
+    frame_count: u64,
+    limit: Option<u64>,
+}
+
+const BARS: [[u8; 3]; 7] = [
+    [192, 192, 192],
+    [192, 192, 0],
+    [0, 192, 192],
+    [0, 192, 0],
+    [192, 0, 192],
+    [192, 0, 0],
+    [0, 0, 192],
+];
+
+// 3x5 bitmaps for the digits 0-9, one row per entry, the low three bits are pixels.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+impl TestPatternSource {
+    pub fn new(width: u32, height: u32) -> Self {
+        TestPatternSource {
+            width,
+            height,
+            frame_count: 0,
+            limit: None,
+        }
+    }
+
+    /// End the stream after `frames` frames instead of running forever.
+    pub fn with_limit(mut self, frames: u64) -> Self {
+        self.limit = Some(frames);
+        self
+    }
+
+    fn fill(&self, data: &mut [u8], x: u32, y: u32, w: u32, h: u32, rgb: [u8; 3]) {
+        for row in y..(y + h).min(self.height) {
+            for col in x..(x + w).min(self.width) {
+                let idx = (row * self.width + col) as usize * BYTES_PER_PIXEL;
+                data[idx..idx + 4].copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+            }
+        }
+    }
+
+    fn draw_counter(&self, data: &mut [u8]) {
+        let scale = (self.height / 40).max(1);
+        let text = self.frame_count.to_string();
+        for (i, digit) in text.bytes().enumerate() {
+            let glyph = DIGITS[(digit - b'0') as usize];
+            let origin_x = scale * 2 + i as u32 * scale * 4;
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        let x = origin_x + col * scale;
+                        let y = scale * 2 + row as u32 * scale;
+                        self.fill(data, x, y, scale, scale, [255, 255, 255]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FrameSource for TestPatternSource {
+    fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        if self.limit.is_some_and(|limit| self.frame_count >= limit) {
+            return Ok(None);
+        }
+        if self.width == 0 || self.height == 0 {
+            bail!("Test pattern size {}x{} is empty", self.width, self.height);
+        }
+        let mut data = vec![0; self.width as usize * self.height as usize * BYTES_PER_PIXEL];
+
+        let bar_width = self.width.div_ceil(BARS.len() as u32);
+        for (i, rgb) in BARS.iter().enumerate() {
+            self.fill(
+                &mut data,
+                i as u32 * bar_width,
+                0,
+                bar_width,
+                self.height,
+                *rgb,
+            );
+        }
+
+        let box_size = (self.height / 8).max(1);
+        let travel = self.width.saturating_sub(box_size).max(1) as u64;
+        let box_x = (self.frame_count * 8 % travel) as u32;
+        let box_y = self.height.saturating_sub(box_size) / 2;
+        self.fill(&mut data, box_x, box_y, box_size, box_size, [255, 255, 255]);
+        self.draw_counter(&mut data);
+
+        self.frame_count += 1;
+        Ok(Some(RawFrame::new(data, self.width, self.height)))
+    }
+}
+
+/// Source cycling through the PNG/JPEG images of a directory in file name order.
+pub struct ImageDirSource {
+    paths: Vec<PathBuf>,
+    index: usize,
+    looping: bool,
+}
+
+impl ImageDirSource {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read image directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| {
+                        matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg")
+                    })
+            })
+            .collect();
+        if paths.is_empty() {
+            bail!("No PNG or JPEG images found in {}", dir.display());
+        }
+        paths.sort();
+        Ok(ImageDirSource {
+            paths,
+            index: 0,
+            looping: true,
+        })
+    }
+
+    /// Stop after the last image instead of starting over.
+    pub fn once(mut self) -> Self {
+        self.looping = false;
+        self
+    }
+}
+
+impl FrameSource for ImageDirSource {
+    fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        if self.index == self.paths.len() {
+            if !self.looping {
+                return Ok(None);
+            }
+            self.index = 0;
+        }
+        let path = &self.paths[self.index];
+        self.index += 1;
+        let img = image::open(path)
+            .with_context(|| format!("Failed to decode {}", path.display()))?
+            .to_rgba8();
+        let (width, height) = img.dimensions();
+        Ok(Some(RawFrame::new(img.into_raw(), width, height)))
+    }
+}
+
+/// Source reading 4:2:0 frames from a raw YUV4MPEG2 (`.y4m`) file.
+pub struct Y4mSource {
+    reader: BufReader<File>,
+    width: u32,
+    height: u32,
+    frame_rate: Option<(u32, u32)>,
+}
+
+impl Y4mSource {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+
+        let mut params = header.split_whitespace();
+        if params.next() != Some("YUV4MPEG2") {
+            bail!("{} is not a YUV4MPEG2 file", path.display());
+        }
+        let (mut width, mut height, mut frame_rate) = (0, 0, None);
+        for param in params {
+            let mut chars = param.chars();
+            let tag = chars.next();
+            let value = chars.as_str();
+            match tag {
+                Some('W') => {
+                    width = value
+                        .parse()
+                        .with_context(|| format!("Invalid Y4M width {}", param))?
+                }
+                Some('H') => {
+                    height = value
+                        .parse()
+                        .with_context(|| format!("Invalid Y4M height {}", param))?
+                }
+                Some('F') => {
+                    frame_rate = value
+                        .split_once(':')
+                        .and_then(|(n, d)| Some((n.parse().ok()?, d.parse().ok()?)))
+                }
+                // Only 8-bit 4:2:0; the variants differ in chroma siting alone.
+                Some('C') if !matches!(value, "420" | "420jpeg" | "420paldv" | "420mpeg2") => {
+                    bail!(
+                        "Unsupported Y4M colorspace C{}, only 8-bit 4:2:0 is supported",
+                        value
+                    )
+                }
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            bail!("Y4M header is missing the frame size");
+        }
+        if (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(BYTES_PER_PIXEL))
+            .is_none()
+        {
+            bail!("Y4M frame size {}x{} is too large", width, height);
+        }
+        Ok(Y4mSource {
+            reader,
+            width,
+            height,
+            frame_rate,
+        })
+    }
+
+    /// Frame rate declared in the file header as numerator and denominator.
+    pub fn frame_rate(&self) -> Option<(u32, u32)> {
+        self.frame_rate
+    }
+}
+
+impl FrameSource for Y4mSource {
+    fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        let mut marker = String::new();
+        if self.reader.read_line(&mut marker)? == 0 {
+            return Ok(None);
+        }
+        if !marker.starts_with("FRAME") {
+            bail!("Malformed Y4M frame header: {:?}", marker.trim_end());
+        }
+
+        let (w, h) = (self.width as usize, self.height as usize);
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+        let mut yuv = vec![0; w * h + 2 * cw * ch];
+        self.reader.read_exact(&mut yuv)?;
+        let (y_plane, chroma) = yuv.split_at(w * h);
+        let (u_plane, v_plane) = chroma.split_at(cw * ch);
+
+        let mut data = vec![0; w * h * BYTES_PER_PIXEL];
+        for row in 0..h {
+            for col in 0..w {
+                let y = y_plane[row * w + col] as f32;
+                let u = u_plane[(row / 2) * cw + col / 2] as f32 - 128.0;
+                let v = v_plane[(row / 2) * cw + col / 2] as f32 - 128.0;
+                let idx = (row * w + col) * BYTES_PER_PIXEL;
+                data[idx] = (y + 1.402 * v).clamp(0.0, 255.0) as u8;
+                data[idx + 1] = (y - 0.344 * u - 0.714 * v).clamp(0.0, 255.0) as u8;
+                data[idx + 2] = (y + 1.772 * u).clamp(0.0, 255.0) as u8;
+                data[idx + 3] = 255;
+            }
+        }
+        Ok(Some(RawFrame::new(data, self.width, self.height)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("webrtc_client_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_pattern_moves_and_stops_at_limit() {
+        let mut source = TestPatternSource::new(160, 90).with_limit(2);
+        let first = source.next_frame().unwrap().unwrap();
+        let second = source.next_frame().unwrap().unwrap();
+        assert_eq!(first.data.len(), 160 * 90 * BYTES_PER_PIXEL);
+        assert_ne!(first.data, second.data);
+        assert!(source.next_frame().unwrap().is_none());
+
+        assert!(TestPatternSource::new(4, 1).next_frame().is_ok());
+        assert!(TestPatternSource::new(4, 0).next_frame().is_err());
+    }
+
+    #[test]
+    fn y4m_frames_are_converted_to_rgba() {
+        let path = temp_path("gray.y4m");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "YUV4MPEG2 W4 H2 F30:1 Ip C420jpeg").unwrap();
+        writeln!(file, "FRAME").unwrap();
+        file.write_all(&[128; 4 * 2 + 2 * 2]).unwrap();
+        drop(file);
+
+        let mut source = Y4mSource::open(&path).unwrap();
+        assert_eq!(source.frame_rate(), Some((30, 1)));
+        let frame = source.next_frame().unwrap().unwrap();
+        assert_eq!((frame.width, frame.height), (4, 2));
+        assert_eq!(&frame.data[..4], &[128, 128, 128, 255]);
+        assert!(source.next_frame().unwrap().is_none());
+
+        // Unknown parameters may be any UTF-8; a bad size is an error, not a panic.
+        std::fs::write(&path, "YUV4MPEG2 W4 H2 \u{e9}x\n").unwrap();
+        assert!(Y4mSource::open(&path).is_ok());
+        std::fs::write(&path, "YUV4MPEG2 W\u{e9} H2\n").unwrap();
+        assert!(Y4mSource::open(&path).is_err());
+        // High bit depth 4:2:0 has twice the bytes per sample.
+        std::fs::write(&path, "YUV4MPEG2 W4 H2 C420p10\n").unwrap();
+        assert!(Y4mSource::open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn image_dir_reads_images_in_order() {
+        let dir = temp_path("images");
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255]))
+            .save(dir.join("a.png"))
+            .unwrap();
+        image::RgbaImage::from_pixel(3, 1, image::Rgba([4, 5, 6, 255]))
+            .save(dir.join("b.png"))
+            .unwrap();
+
+        let mut source = ImageDirSource::open(&dir).unwrap().once();
+        assert_eq!(source.next_frame().unwrap().unwrap().width, 2);
+        assert_eq!(source.next_frame().unwrap().unwrap().width, 3);
+        assert!(source.next_frame().unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
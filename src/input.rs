@@ -0,0 +1,447 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use webrtc::data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel};
+
+use crate::auth::{self, Role};
+
+/// Label of the data channel carrying [`InputMessage`]s from the viewer.
+pub const CONTROL_CHANNEL: &str = "control";
+
+/// Most wheel notches one [`InputMessage::Scroll`] may scroll in each direction.
+pub const MAX_SCROLL_NOTCHES: i32 = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+/// Input from the viewer, sent as JSON over the [`CONTROL_CHANNEL`] data channel.
+///
+/// Positions are in the viewer's `width` x `height` coordinate space, such as the decoded
+/// frame or the element showing it, and are scaled to the display on arrival. The frame is
+/// downscaled before it is sent, so the two sizes rarely match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum InputMessage {
+    MouseMove {
+        x: f64,
+        y: f64,
+        width: u32,
+        height: u32,
+    },
+    /// Press or release at the current pointer position; a click is one of each.
+    MouseButton { button: MouseButton, pressed: bool },
+    /// Wheel notches; positive `dy` scrolls down, positive `dx` right. At most
+    /// [`MAX_SCROLL_NOTCHES`] each way are applied.
+    Scroll { dx: i32, dy: i32 },
+    /// `key` is the DOM `KeyboardEvent.key` value, such as `"a"`, `"Enter"` or `"Shift"`.
+    Key { key: String, pressed: bool },
+}
+
+impl InputMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self).unwrap()
+    }
+
+    /// The event to inject into a display of `display_width` x `display_height` pixels.
+    pub fn to_event(&self, display_width: u32, display_height: u32) -> Result<InputEvent> {
+        Ok(match self {
+            InputMessage::MouseMove {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                if *width == 0 || *height == 0 {
+                    bail!("Mouse position in an empty {}x{} view", width, height);
+                }
+                InputEvent::MouseMove {
+                    x: scale(*x, *width, display_width),
+                    y: scale(*y, *height, display_height),
+                }
+            }
+            InputMessage::MouseButton { button, pressed } => InputEvent::MouseButton {
+                button: *button,
+                pressed: *pressed,
+            },
+            InputMessage::Scroll { dx, dy } => {
+                let notches = |n: i32| n.clamp(-MAX_SCROLL_NOTCHES, MAX_SCROLL_NOTCHES);
+                InputEvent::Scroll {
+                    dx: notches(*dx),
+                    dy: notches(*dy),
+                }
+            }
+            InputMessage::Key { key, pressed } => InputEvent::Key {
+                key: key.clone(),
+                pressed: *pressed,
+            },
+        })
+    }
+}
+
+/// `value` in a space `from` wide as a pixel of one `to` wide, clamped to the display.
+fn scale(value: f64, from: u32, to: u32) -> i32 {
+    let scaled = value * to as f64 / from as f64;
+    scaled.clamp(0.0, to.saturating_sub(1) as f64) as i32
+}
+
+/// An [`InputMessage`] in display coordinates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    MouseMove { x: i32, y: i32 },
+    MouseButton { button: MouseButton, pressed: bool },
+    Scroll { dx: i32, dy: i32 },
+    Key { key: String, pressed: bool },
+}
+
+/// Injects input into the shared display as if it came from local devices.
+pub trait InputInjector: Send {
+    /// Size of the display events are injected into.
+    fn display_size(&mut self) -> Result<(u32, u32)>;
+
+    fn inject(&mut self, event: &InputEvent) -> Result<()>;
+}
+
+/// Records injected events instead of injecting them, for tests. Clones share the record.
+#[derive(Debug, Clone)]
+pub struct RecordingInjector {
+    display: (u32, u32),
+    events: Arc<Mutex<Vec<InputEvent>>>,
+}
+
+impl RecordingInjector {
+    pub fn new(display_width: u32, display_height: u32) -> Self {
+        RecordingInjector {
+            display: (display_width, display_height),
+            events: Arc::default(),
+        }
+    }
+
+    pub fn events(&self) -> Vec<InputEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl InputInjector for RecordingInjector {
+    fn display_size(&mut self) -> Result<(u32, u32)> {
+        Ok(self.display)
+    }
+
+    fn inject(&mut self, event: &InputEvent) -> Result<()> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+/// Applies [`InputMessage`]s from the viewer through an [`InputInjector`].
+pub struct RemoteControl {
+    injector: Box<dyn InputInjector>,
+}
+
+impl RemoteControl {
+    pub fn new(injector: Box<dyn InputInjector>) -> Self {
+        RemoteControl { injector }
+    }
+
+    /// Parse one message from the control channel and inject it.
+    pub fn handle(&mut self, text: &str) -> Result<()> {
+        if !auth::permits(Role::Control) {
+            bail!("Session is not authorized to control the display");
+        }
+        let message: InputMessage = serde_json::from_str(text)?;
+        let (width, height) = self.injector.display_size()?;
+        let event = message.to_event(width, height)?;
+        self.injector.inject(&event)
+    }
+
+    /// Handle every text message arriving on `channel`. Injection runs on the blocking pool;
+    /// messages are still handled one at a time, in order.
+    pub fn attach(self, channel: &RTCDataChannel) {
+        let control = Arc::new(Mutex::new(self));
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let control = control.clone();
+            Box::pin(async move {
+                if !message.is_string {
+                    return;
+                }
+                let text = String::from_utf8_lossy(&message.data).into_owned();
+                let handled =
+                    tokio::task::spawn_blocking(move || control.lock().unwrap().handle(&text))
+                        .await;
+                match handled {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("Error handling control message {}", e),
+                    Err(e) => eprintln!("Control message handling panicked {}", e),
+                }
+            })
+        }));
+    }
+}
+
+/// Input injector for the current platform, if there is one.
+pub fn default_input_injector() -> Result<Option<Box<dyn InputInjector>>> {
+    #[cfg(target_os = "linux")]
+    {
+        Ok(Some(Box::new(x11::X11InputInjector::connect()?)))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(None)
+    }
+}
+
+/// X11 keysym of a DOM `KeyboardEvent.key` value.
+pub fn keysym(key: &str) -> Option<u32> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        // Latin-1 keysyms equal their code point; the rest of Unicode is offset.
+        let c = c as u32;
+        return Some(if c < 0x100 { c } else { 0x0100_0000 | c });
+    }
+    if let Some(n) = key.strip_prefix('F').and_then(|n| n.parse::<u32>().ok()) {
+        return (1..=24).contains(&n).then_some(0xffbe + n - 1);
+    }
+    Some(match key {
+        "Backspace" => 0xff08,
+        "Tab" => 0xff09,
+        "Enter" => 0xff0d,
+        "Pause" => 0xff13,
+        "ScrollLock" => 0xff14,
+        "Escape" => 0xff1b,
+        "Home" => 0xff50,
+        "ArrowLeft" => 0xff51,
+        "ArrowUp" => 0xff52,
+        "ArrowRight" => 0xff53,
+        "ArrowDown" => 0xff54,
+        "PageUp" => 0xff55,
+        "PageDown" => 0xff56,
+        "End" => 0xff57,
+        "PrintScreen" => 0xff61,
+        "Insert" => 0xff63,
+        "ContextMenu" => 0xff67,
+        "NumLock" => 0xff7f,
+        "Shift" => 0xffe1,
+        "Control" => 0xffe3,
+        "CapsLock" => 0xffe5,
+        "Meta" => 0xffe7,
+        "Alt" => 0xffe9,
+        "AltGraph" => 0xfe03,
+        "Delete" => 0xffff,
+        _ => return None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+pub mod x11 {
+    use anyhow::{anyhow, Result};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::{
+        ConnectionExt as _, Window, BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT, KEY_PRESS_EVENT,
+        KEY_RELEASE_EVENT, MOTION_NOTIFY_EVENT,
+    };
+    use x11rb::protocol::xtest::ConnectionExt as _;
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::CURRENT_TIME;
+
+    use super::{keysym, InputEvent, InputInjector, MouseButton};
+
+    /// Injects input through the X11 XTEST extension into the display screen capture uses.
+    pub struct X11InputInjector {
+        conn: RustConnection,
+        root: Window,
+        /// Position of the captured display on the root window.
+        offset: (i16, i16),
+        size: (u32, u32),
+        min_keycode: u8,
+        keysyms_per_keycode: usize,
+        keysyms: Vec<u32>,
+    }
+
+    impl X11InputInjector {
+        pub fn connect() -> Result<Self> {
+            let (conn, screen_num) = x11rb::connect(None)?;
+            conn.xtest_get_version(2, 2)?.reply()?;
+            conn.randr_query_version(1, 5)?.reply()?;
+            let setup = conn.setup();
+            let mut monitors = vec![];
+            for screen in &setup.roots {
+                for monitor in conn
+                    .randr_get_monitors(screen.root, true)?
+                    .reply()?
+                    .monitors
+                {
+                    monitors.push((screen.root, monitor));
+                }
+            }
+            // Pick the display the way scrap's `Display::primary` does: the primary monitor,
+            // or the first one when none is marked primary.
+            let primary = monitors.iter().position(|(_, m)| m.primary).unwrap_or(0);
+            let (root, offset, size) = match monitors.get(primary) {
+                Some((root, monitor)) => (
+                    *root,
+                    (monitor.x, monitor.y),
+                    (monitor.width as u32, monitor.height as u32),
+                ),
+                None => {
+                    let screen = &setup.roots[screen_num];
+                    (
+                        screen.root,
+                        (0, 0),
+                        (
+                            screen.width_in_pixels as u32,
+                            screen.height_in_pixels as u32,
+                        ),
+                    )
+                }
+            };
+            let (min_keycode, max_keycode) = (setup.min_keycode, setup.max_keycode);
+            let mapping = conn
+                .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)?
+                .reply()?;
+            Ok(X11InputInjector {
+                conn,
+                root,
+                offset,
+                size,
+                min_keycode,
+                keysyms_per_keycode: mapping.keysyms_per_keycode.max(1) as usize,
+                keysyms: mapping.keysyms,
+            })
+        }
+
+        fn keycode(&self, key: &str) -> Result<u8> {
+            let keysym = keysym(key).ok_or_else(|| anyhow!("Unknown key {:?}", key))?;
+            let index = self
+                .keysyms
+                .iter()
+                .position(|k| *k == keysym)
+                .ok_or_else(|| anyhow!("No keycode for key {:?}", key))?;
+            Ok(self.min_keycode + (index / self.keysyms_per_keycode) as u8)
+        }
+
+        fn fake(&self, kind: u8, detail: u8, x: i16, y: i16) -> Result<()> {
+            self.conn
+                .xtest_fake_input(kind, detail, CURRENT_TIME, self.root, x, y, 0)?;
+            Ok(())
+        }
+    }
+
+    impl InputInjector for X11InputInjector {
+        fn display_size(&mut self) -> Result<(u32, u32)> {
+            Ok(self.size)
+        }
+
+        fn inject(&mut self, event: &InputEvent) -> Result<()> {
+            let press = |pressed: bool, press, release| if pressed { press } else { release };
+            match event {
+                InputEvent::MouseMove { x, y } => {
+                    // X11 coordinates are 16 bit; a position past them is not on any display.
+                    let root = |offset: i16, position: i32| {
+                        i16::try_from(i32::from(offset).saturating_add(position))
+                            .map_err(|_| anyhow!("Pointer position {} is off the screen", position))
+                    };
+                    let x = root(self.offset.0, *x)?;
+                    let y = root(self.offset.1, *y)?;
+                    self.fake(MOTION_NOTIFY_EVENT, 0, x, y)?
+                }
+                InputEvent::MouseButton { button, pressed } => {
+                    let button = match button {
+                        MouseButton::Left => 1,
+                        MouseButton::Middle => 2,
+                        MouseButton::Right => 3,
+                    };
+                    let kind = press(*pressed, BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT);
+                    self.fake(kind, button, 0, 0)?;
+                }
+                InputEvent::Scroll { dx, dy } => {
+                    // X11 scrolls with buttons 4 to 7, one click per notch.
+                    for (notches, negative, positive) in [(*dy, 4, 5), (*dx, 6, 7)] {
+                        let button = if notches < 0 { negative } else { positive };
+                        for _ in 0..notches.unsigned_abs() {
+                            self.fake(BUTTON_PRESS_EVENT, button, 0, 0)?;
+                            self.fake(BUTTON_RELEASE_EVENT, button, 0, 0)?;
+                        }
+                    }
+                }
+                InputEvent::Key { key, pressed } => {
+                    let keycode = self.keycode(key)?;
+                    let kind = press(*pressed, KEY_PRESS_EVENT, KEY_RELEASE_EVENT);
+                    self.fake(kind, keycode, 0, 0)?;
+                }
+            }
+            self.conn.flush()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_scaled_back_to_the_display() {
+        let injector = RecordingInjector::new(1920, 1080);
+        let mut control = RemoteControl::new(Box::new(injector.clone()));
+        // The viewer sees the 1280x720 frame the display was downscaled to.
+        for message in [
+            InputMessage::MouseMove {
+                x: 640.0,
+                y: 360.0,
+                width: 1280,
+                height: 720,
+            },
+            InputMessage::MouseMove {
+                x: 1300.0,
+                y: -5.0,
+                width: 1280,
+                height: 720,
+            },
+            InputMessage::MouseButton {
+                button: MouseButton::Left,
+                pressed: true,
+            },
+        ] {
+            control.handle(&message.to_json()).unwrap();
+        }
+        assert_eq!(
+            injector.events(),
+            vec![
+                InputEvent::MouseMove { x: 960, y: 540 },
+                InputEvent::MouseMove { x: 1919, y: 0 },
+                InputEvent::MouseButton {
+                    button: MouseButton::Left,
+                    pressed: true
+                },
+            ]
+        );
+        assert!(control.handle("{\"type\":\"teleport\"}").is_err());
+    }
+
+    #[test]
+    fn messages_use_the_documented_schema() {
+        let json = r#"{"type":"key","key":"Enter","pressed":false}"#;
+        let message: InputMessage = serde_json::from_str(json).unwrap();
+        assert_eq!(message.to_json(), json);
+        assert_eq!(
+            serde_json::from_str::<InputMessage>(r#"{"type":"scroll","dx":0,"dy":-2}"#).unwrap(),
+            InputMessage::Scroll { dx: 0, dy: -2 }
+        );
+        let flood = InputMessage::Scroll {
+            dx: i32::MAX,
+            dy: -500,
+        };
+        assert_eq!(
+            flood.to_event(1920, 1080).unwrap(),
+            InputEvent::Scroll { dx: 10, dy: -10 }
+        );
+        assert_eq!(keysym("a"), Some(0x61));
+        assert_eq!(keysym("F5"), Some(0xffc2));
+        assert_eq!(keysym("Hyper"), None);
+    }
+}
@@ -0,0 +1,426 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::{Duration, Instant};
+use webrtc::rtp::packet::Packet;
+use webrtc::rtp::packetizer::Depacketizer;
+
+// A jump this large is a stream reset or a long outage, not something NACK can repair.
+const MAX_TRACKED_GAP: u64 = 1000;
+
+/// Where an arriving packet falls in its stream, by sequence number extended past 16 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// Ahead of every packet before it; any sequence numbers skipped are now missing.
+    Next(u64),
+    /// Filled a gap, usually as a NACK retransmission.
+    Recovered(u64),
+    /// Behind the newest packet without filling a gap: a duplicate or one given up on.
+    Old(u64),
+    /// Jumped too far ahead to track; the `lost` packets missing before it were given up.
+    Reset { sequence: u64, lost: usize },
+}
+
+/// Sequence numbers of an RTP stream that have not arrived, and since when.
+#[derive(Debug, Default)]
+pub struct SequenceGaps {
+    /// Highest sequence number seen, extended past 16 bits.
+    highest: Option<u64>,
+    missing: BTreeMap<u64, Instant>,
+}
+
+impl SequenceGaps {
+    /// Record a packet arriving at `now`.
+    pub fn arrive(&mut self, sequence: u16, now: Instant) -> Arrival {
+        let Some(highest) = self.highest else {
+            // Start high enough that earlier packets never go below zero.
+            let extended = (1 << 32) + sequence as u64;
+            self.highest = Some(extended);
+            return Arrival::Next(extended);
+        };
+        let extended =
+            highest.wrapping_add_signed(sequence.wrapping_sub(highest as u16) as i16 as i64);
+        self.highest = Some(extended.max(highest));
+        if extended <= highest {
+            return match self.missing.remove(&extended) {
+                Some(_) => Arrival::Recovered(extended),
+                None => Arrival::Old(extended),
+            };
+        }
+        if extended - highest > MAX_TRACKED_GAP {
+            let lost = self.missing.len();
+            self.missing.clear();
+            return Arrival::Reset {
+                sequence: extended,
+                lost,
+            };
+        }
+        for gap in highest + 1..extended {
+            self.missing.insert(gap, now);
+        }
+        Arrival::Next(extended)
+    }
+
+    /// Whether a packet has been missing for longer than `window`.
+    pub fn expired(&self, now: Instant, window: Duration) -> bool {
+        self.missing
+            .values()
+            .any(|since| now.duration_since(*since) > window)
+    }
+
+    /// The oldest sequence number still missing.
+    pub fn first(&self) -> Option<u64> {
+        self.missing.keys().next().copied()
+    }
+
+    /// Stop waiting for the packets missing before `sequence`; returns how many there were.
+    pub fn give_up_before(&mut self, sequence: u64) -> usize {
+        let later = self.missing.split_off(&sequence);
+        std::mem::replace(&mut self.missing, later).len()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitterBufferConfig {
+    /// How long a missing packet may stay missing while NACK recovers it.
+    pub nack_window: Duration,
+    /// Packets held at most; beyond this the oldest frame is given up. Must fit a whole
+    /// frame: a raw 1080p RGBA frame is about 7000 packets.
+    pub max_packets: usize,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        JitterBufferConfig {
+            nack_window: Duration::from_millis(300),
+            max_packets: 8192,
+        }
+    }
+}
+
+/// A frame whose packets all arrived, depacketized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledFrame {
+    pub timestamp: u32,
+    pub first_sequence: u16,
+    pub packets: usize,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitterBufferStats {
+    pub received: u64,
+    pub duplicates: u64,
+    /// Arrived after the frame they belong to was emitted or given up.
+    pub late: u64,
+    /// Filled a gap in time, usually as a NACK retransmission.
+    pub recovered: u64,
+    /// Still missing when the NACK window ran out.
+    pub lost: u64,
+    pub frames: u64,
+    /// Frames given up on because of lost packets or a payload that did not depacketize.
+    pub dropped_frames: u64,
+}
+
+#[derive(Debug)]
+struct BufferedPacket {
+    timestamp: u32,
+    marker: bool,
+    payload: Bytes,
+}
+
+/// Receive-side reassembly of video frames from RTP packets.
+///
+/// Packets are ordered by sequence number, so reordering is transparent. A gap holds back
+/// the frames behind it for up to `nack_window` while the NACK interceptor asks for the
+/// missing packets; only complete frames are emitted, in order. When a gap outlives the
+/// window, the frames it broke are dropped and decoding resumes at the next frame start,
+/// which needs a keyframe to decode.
+pub struct VideoJitterBuffer {
+    config: JitterBufferConfig,
+    depacketizer: Box<dyn Depacketizer + Send>,
+    gaps: SequenceGaps,
+    /// First sequence number of the next frame to emit; `None` until a frame start arrives.
+    next: Option<u64>,
+    packets: BTreeMap<u64, BufferedPacket>,
+    frames: VecDeque<AssembledFrame>,
+    stats: JitterBufferStats,
+}
+
+impl VideoJitterBuffer {
+    pub fn new(depacketizer: Box<dyn Depacketizer + Send>, config: JitterBufferConfig) -> Self {
+        VideoJitterBuffer {
+            config,
+            depacketizer,
+            gaps: SequenceGaps::default(),
+            next: None,
+            packets: BTreeMap::new(),
+            frames: VecDeque::new(),
+            stats: JitterBufferStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitterBufferStats {
+        self.stats
+    }
+
+    /// Buffer a received packet; returns whether frames were lost, so a keyframe is needed.
+    pub fn push(&mut self, packet: &Packet, now: Instant) -> bool {
+        self.stats.received += 1;
+        match self.gaps.arrive(packet.header.sequence_number, now) {
+            Arrival::Reset { sequence, lost } => {
+                self.stats.lost += lost as u64;
+                self.discard_until(sequence);
+                self.next = None;
+                self.insert(sequence, packet, now);
+                true
+            }
+            Arrival::Recovered(sequence) => {
+                self.stats.recovered += 1;
+                self.insert(sequence, packet, now)
+            }
+            Arrival::Next(sequence) | Arrival::Old(sequence) => self.insert(sequence, packet, now),
+        }
+    }
+
+    /// Next complete frame, in sequence order.
+    pub fn pop_frame(&mut self) -> Option<AssembledFrame> {
+        self.frames.pop_front()
+    }
+
+    /// Give up on packets missing for longer than the NACK window, or on the oldest frame
+    /// when the buffer is full; returns whether packets were lost or discarded.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let expired = self.gaps.expired(now, self.config.nack_window);
+        let after = match (self.gaps.first(), self.packets.keys().next()) {
+            (Some(gap), _) if expired => gap,
+            (_, Some(oldest)) if self.packets.len() > self.config.max_packets => oldest + 1,
+            _ => return false,
+        };
+        // Resume at the first frame start past the gap or the oldest frame; everything
+        // before it is undecodable.
+        let resume = self
+            .packets
+            .range(after..)
+            .find(|(_, p)| self.depacketizer.is_partition_head(&p.payload))
+            .map(|(sequence, _)| *sequence);
+        let resume_at = resume.unwrap_or(u64::MAX);
+        let lost = self.gaps.give_up_before(resume_at);
+        self.stats.lost += lost as u64;
+        let held = self.packets.len();
+        self.discard_until(resume_at);
+        let discarded = held - self.packets.len();
+        self.next = resume;
+        let broken = self.assemble();
+        lost > 0 || discarded > 0 || broken
+    }
+
+    fn insert(&mut self, sequence: u64, packet: &Packet, now: Instant) -> bool {
+        match self.next {
+            Some(next) if sequence < next => {
+                self.stats.late += 1;
+                return self.expire(now);
+            }
+            Some(_) => {}
+            None if self.depacketizer.is_partition_head(&packet.payload) => {
+                self.next = Some(sequence);
+                self.discard_until(sequence);
+                self.gaps.give_up_before(sequence);
+            }
+            // Nothing before the first frame start can be decoded.
+            None => return false,
+        }
+        if self.packets.contains_key(&sequence) {
+            self.stats.duplicates += 1;
+            return false;
+        }
+        self.packets.insert(
+            sequence,
+            BufferedPacket {
+                timestamp: packet.header.timestamp,
+                marker: packet.header.marker,
+                payload: packet.payload.clone(),
+            },
+        );
+        let broken = self.assemble();
+        self.expire(now) || broken
+    }
+
+    /// Move complete frames at the head of the buffer to the output; returns whether one
+    /// failed to depacketize.
+    fn assemble(&mut self) -> bool {
+        let mut broken = false;
+        while let Some(start) = self.next {
+            let Some(first) = self.packets.get(&start) else {
+                break;
+            };
+            let timestamp = first.timestamp;
+            let mut end = start;
+            let complete = loop {
+                match self.packets.get(&end) {
+                    None => break false,
+                    // The next frame began without a marker on this one.
+                    Some(p) if p.timestamp != timestamp => {
+                        end -= 1;
+                        break true;
+                    }
+                    Some(p) if p.marker => break true,
+                    Some(_) => end += 1,
+                }
+            };
+            if !complete {
+                break;
+            }
+            let mut data = BytesMut::new();
+            let mut failed = false;
+            for sequence in start..=end {
+                let Some(packet) = self.packets.remove(&sequence) else {
+                    continue;
+                };
+                match self.depacketizer.depacketize(&packet.payload) {
+                    Ok(payload) => data.extend_from_slice(&payload),
+                    Err(e) => {
+                        eprintln!("Error depacketizing packet {} {}", sequence as u16, e);
+                        failed = true;
+                    }
+                }
+            }
+            self.next = Some(end + 1);
+            if failed {
+                self.stats.dropped_frames += 1;
+                broken = true;
+                continue;
+            }
+            self.stats.frames += 1;
+            self.frames.push_back(AssembledFrame {
+                timestamp,
+                first_sequence: start as u16,
+                packets: (end - start + 1) as usize,
+                data: data.freeze(),
+            });
+        }
+        broken
+    }
+
+    /// Drop every buffered packet before `sequence`, counting the frames they belonged to.
+    fn discard_until(&mut self, sequence: u64) {
+        let kept = self.packets.split_off(&sequence);
+        let discarded = std::mem::replace(&mut self.packets, kept);
+        let mut frames = discarded
+            .values()
+            .map(|p| p.timestamp)
+            .collect::<BTreeSet<_>>()
+            .len();
+        if self.next.is_some_and(|next| next < sequence) {
+            // The frame waiting on the gap is lost even if none of its packets arrived.
+            frames = frames.max(1);
+        }
+        self.stats.dropped_frames += frames as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::codecs::vp8::Vp8Packet;
+    use webrtc::rtp::header::Header;
+
+    // VP8 payload descriptor with the start-of-partition bit, then the sequence number as data.
+    // The depacketizer wants at least four bytes.
+    fn packet(sequence: u16, timestamp: u32, head: bool, marker: bool) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number: sequence,
+                timestamp,
+                marker,
+                ..Default::default()
+            },
+            payload: Bytes::from(vec![if head { 0x10 } else { 0x00 }, sequence as u8, 0, 0]),
+        }
+    }
+
+    fn buffer() -> VideoJitterBuffer {
+        VideoJitterBuffer::new(
+            Box::new(Vp8Packet::default()),
+            JitterBufferConfig::default(),
+        )
+    }
+
+    #[test]
+    fn reorders_and_waits_for_retransmissions() {
+        let mut buffer = buffer();
+        let now = Instant::now();
+        // Two frames across the sequence number wrap, arriving out of order with a gap.
+        assert!(!buffer.push(&packet(65534, 1000, true, false), now));
+        assert!(!buffer.push(&packet(0, 4000, true, false), now));
+        assert!(!buffer.push(&packet(65535, 1000, false, true), now));
+        let first = buffer.pop_frame().unwrap();
+        assert_eq!((first.timestamp, first.packets), (1000, 2));
+        assert_eq!(first.data, Bytes::from_static(&[254, 0, 0, 255, 0, 0]));
+
+        assert!(!buffer.push(&packet(2, 4000, false, true), now));
+        assert!(buffer.pop_frame().is_none());
+        assert!(!buffer.push(
+            &packet(1, 4000, false, false),
+            now + Duration::from_millis(100)
+        ));
+        let second = buffer.pop_frame().unwrap();
+        assert_eq!((second.first_sequence, second.packets), (0, 3));
+        assert!(!buffer.push(&packet(1, 4000, false, false), now));
+        assert_eq!(
+            buffer.stats(),
+            JitterBufferStats {
+                received: 6,
+                late: 1,
+                recovered: 2,
+                frames: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unrecovered_loss_drops_the_frame_and_resumes_at_the_next() {
+        let mut buffer = buffer();
+        let now = Instant::now();
+        buffer.push(&packet(10, 1000, true, false), now);
+        buffer.push(&packet(12, 1000, false, true), now);
+        buffer.push(&packet(13, 4000, true, true), now);
+        assert!(buffer.pop_frame().is_none());
+        // Packet 11 never comes back.
+        assert!(buffer.push(
+            &packet(14, 7000, true, true),
+            now + Duration::from_millis(400)
+        ));
+        let resumed: Vec<u32> = std::iter::from_fn(|| buffer.pop_frame())
+            .map(|f| f.timestamp)
+            .collect();
+        assert_eq!(resumed, [4000, 7000]);
+        let stats = buffer.stats();
+        assert_eq!((stats.lost, stats.dropped_frames, stats.frames), (1, 1, 2));
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest_frame_once() {
+        let mut buffer = VideoJitterBuffer::new(
+            Box::new(Vp8Packet::default()),
+            JitterBufferConfig {
+                max_packets: 4,
+                ..Default::default()
+            },
+        );
+        let now = Instant::now();
+        // A frame larger than the buffer, with nothing missing.
+        assert!(!buffer.push(&packet(0, 1000, true, false), now));
+        for sequence in 1..4 {
+            assert!(!buffer.push(&packet(sequence, 1000, false, false), now));
+        }
+        assert!(buffer.push(&packet(4, 1000, false, false), now));
+        assert!(!buffer.push(&packet(5, 1000, false, true), now));
+        assert!(buffer.pop_frame().is_none());
+
+        assert!(!buffer.push(&packet(6, 4000, true, true), now));
+        assert_eq!(buffer.pop_frame().unwrap().timestamp, 4000);
+        let stats = buffer.stats();
+        assert_eq!((stats.lost, stats.dropped_frames, stats.frames), (0, 1, 1));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webrtc::rtcp::{
+    packet::Packet,
+    payload_feedbacks::{
+        full_intra_request::{FirEntry, FullIntraRequest},
+        picture_loss_indication::PictureLossIndication,
+    },
+};
+
+use crate::jitter_buffer::{Arrival, SequenceGaps};
+
+/// Why the receiver wants a keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeReason {
+    /// A track just started, so nothing can be decoded until a keyframe arrives.
+    TrackStart,
+    /// Packets went missing and NACK retransmissions did not bring them back in time.
+    UnrecoverableLoss,
+    /// The decoder reported it could not decode what it was given.
+    DecoderError,
+}
+
+/// When and how the receive side asks the sender for keyframes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyframeRequestPolicy {
+    /// How long a missing packet may stay missing while NACK recovers it.
+    pub nack_window: Duration,
+    /// Minimum time between two requests for the same track.
+    pub min_interval: Duration,
+    /// Send FIR instead of PLI, for senders that only honour FIR.
+    pub use_fir: bool,
+}
+
+impl Default for KeyframeRequestPolicy {
+    fn default() -> Self {
+        KeyframeRequestPolicy {
+            nack_window: Duration::from_millis(300),
+            min_interval: Duration::from_secs(1),
+            use_fir: false,
+        }
+    }
+}
+
+/// Counters of the keyframe requests made by the receive side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyframeRequestStats {
+    pub pli_sent: u64,
+    pub fir_sent: u64,
+    /// Requests held back because one for the same track went out less than `min_interval`
+    /// ago; they go out once it has passed.
+    pub suppressed: u64,
+    pub track_start: u64,
+    pub unrecoverable_loss: u64,
+    pub decoder_error: u64,
+}
+
+/// Finds RTP packets that NACK did not recover within the window.
+///
+/// Retransmissions carry the original sequence number, so a gap that gets filled late was
+/// recovered; a gap still open after `nack_window` is lost for good.
+#[derive(Debug)]
+pub struct LossDetector {
+    nack_window: Duration,
+    gaps: SequenceGaps,
+}
+
+impl LossDetector {
+    pub fn new(nack_window: Duration) -> Self {
+        LossDetector {
+            nack_window,
+            gaps: SequenceGaps::default(),
+        }
+    }
+
+    /// Record a received packet; returns whether loss has become unrecoverable.
+    pub fn on_packet(&mut self, sequence: u16, now: Instant) -> bool {
+        if let Arrival::Reset { .. } = self.gaps.arrive(sequence, now) {
+            return true;
+        }
+        self.expired(now)
+    }
+
+    /// Whether a packet has been missing for longer than the NACK window. Forgets the
+    /// missing packets when it has, as the requested keyframe supersedes them.
+    pub fn expired(&mut self, now: Instant) -> bool {
+        let expired = self.gaps.expired(now, self.nack_window);
+        if expired {
+            self.gaps.give_up_before(u64::MAX);
+        }
+        expired
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrackRequests {
+    pending: Option<KeyframeReason>,
+    /// Whether the pending request was already counted as suppressed.
+    held: bool,
+    last_sent: Option<Instant>,
+    fir_sequence: u8,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    pli_sent: AtomicU64,
+    fir_sent: AtomicU64,
+    suppressed: AtomicU64,
+    track_start: AtomicU64,
+    unrecoverable_loss: AtomicU64,
+    decoder_error: AtomicU64,
+}
+
+/// Collects keyframe requests for incoming tracks and rate-limits them per track.
+///
+/// Requests are queued with [`KeyframeRequester::request`] and turned into RTCP packets by
+/// the track's reader with [`KeyframeRequester::take`]. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct KeyframeRequester {
+    policy: KeyframeRequestPolicy,
+    tracks: Arc<Mutex<HashMap<u32, TrackRequests>>>,
+    counters: Arc<Counters>,
+}
+
+impl KeyframeRequester {
+    pub fn new(policy: KeyframeRequestPolicy) -> Self {
+        KeyframeRequester {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> KeyframeRequestPolicy {
+        self.policy
+    }
+
+    /// Ask for a keyframe on the track with `media_ssrc`.
+    pub fn request(&self, media_ssrc: u32, reason: KeyframeReason) {
+        let counter = match reason {
+            KeyframeReason::TrackStart => &self.counters.track_start,
+            KeyframeReason::UnrecoverableLoss => &self.counters.unrecoverable_loss,
+            KeyframeReason::DecoderError => &self.counters.decoder_error,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let mut tracks = self.tracks.lock().unwrap();
+        let pending = &mut tracks.entry(media_ssrc).or_default().pending;
+        // A pending track start keeps its exemption from the rate limit.
+        if *pending != Some(KeyframeReason::TrackStart) {
+            *pending = Some(reason);
+        }
+    }
+
+    /// For decoders to report a frame of the track with `media_ssrc` they could not decode.
+    pub fn report_decoder_error(&self, media_ssrc: u32) {
+        self.request(media_ssrc, KeyframeReason::DecoderError);
+    }
+
+    /// The PLI or FIR to send for a pending request, if one is pending and allowed now.
+    /// A request held back by the rate limit stays pending until `min_interval` has passed.
+    pub fn take(&self, media_ssrc: u32, now: Instant) -> Option<Box<dyn Packet + Send + Sync>> {
+        let mut tracks = self.tracks.lock().unwrap();
+        let track = tracks.get_mut(&media_ssrc)?;
+        let reason = track.pending?;
+        let limited = track
+            .last_sent
+            .is_some_and(|t| now.duration_since(t) < self.policy.min_interval);
+        // A track that just started has never had a keyframe, so it is never held back.
+        if limited && reason != KeyframeReason::TrackStart {
+            if !std::mem::replace(&mut track.held, true) {
+                self.counters.suppressed.fetch_add(1, Ordering::Relaxed);
+            }
+            return None;
+        }
+        track.pending = None;
+        track.held = false;
+        track.last_sent = Some(now);
+        println!("Requesting keyframe for ssrc {media_ssrc}: {reason:?}");
+        if self.policy.use_fir {
+            track.fir_sequence = track.fir_sequence.wrapping_add(1);
+            self.counters.fir_sent.fetch_add(1, Ordering::Relaxed);
+            Some(Box::new(FullIntraRequest {
+                sender_ssrc: 0,
+                media_ssrc,
+                fir: vec![FirEntry {
+                    ssrc: media_ssrc,
+                    sequence_number: track.fir_sequence,
+                }],
+            }))
+        } else {
+            self.counters.pli_sent.fetch_add(1, Ordering::Relaxed);
+            Some(Box::new(PictureLossIndication {
+                sender_ssrc: 0,
+                media_ssrc,
+            }))
+        }
+    }
+
+    /// Stop tracking a track that ended.
+    pub fn remove(&self, media_ssrc: u32) {
+        self.tracks.lock().unwrap().remove(&media_ssrc);
+    }
+
+    pub fn stats(&self) -> KeyframeRequestStats {
+        let c = &self.counters;
+        KeyframeRequestStats {
+            pli_sent: c.pli_sent.load(Ordering::Relaxed),
+            fir_sent: c.fir_sent.load(Ordering::Relaxed),
+            suppressed: c.suppressed.load(Ordering::Relaxed),
+            track_start: c.track_start.load(Ordering::Relaxed),
+            unrecoverable_loss: c.unrecoverable_loss.load(Ordering::Relaxed),
+            decoder_error: c.decoder_error.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_filled_by_retransmission_are_not_loss() {
+        let mut loss = LossDetector::new(Duration::from_millis(300));
+        let now = Instant::now();
+        assert!(!loss.on_packet(65534, now));
+        assert!(!loss.on_packet(1, now));
+        // 65535 and 0 are missing across the wrap; one is retransmitted in time.
+        assert!(!loss.on_packet(65535, now + Duration::from_millis(100)));
+        assert!(!loss.on_packet(2, now + Duration::from_millis(200)));
+        assert!(loss.on_packet(3, now + Duration::from_millis(400)));
+        assert!(!loss.on_packet(4, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn requests_are_rate_limited_per_track() {
+        let requester = KeyframeRequester::new(KeyframeRequestPolicy::default());
+        let now = Instant::now();
+        assert!(requester.take(7, now).is_none());
+
+        requester.request(7, KeyframeReason::TrackStart);
+        assert!(requester.take(7, now).is_some());
+        requester.report_decoder_error(7);
+        assert!(requester.take(7, now + Duration::from_millis(10)).is_none());
+        assert!(requester.take(7, now + Duration::from_millis(20)).is_none());
+        requester.request(8, KeyframeReason::TrackStart);
+        assert!(requester.take(8, now).is_some());
+        // The held back request goes out once the interval has passed, without asking again.
+        assert!(requester.take(7, now + Duration::from_secs(1)).is_some());
+        assert!(requester.take(7, now + Duration::from_secs(3)).is_none());
+        requester.request(7, KeyframeReason::UnrecoverableLoss);
+        assert!(requester.take(7, now + Duration::from_secs(3)).is_some());
+
+        let stats = requester.stats();
+        assert_eq!((stats.pli_sent, stats.suppressed), (4, 1));
+        assert_eq!(
+            (
+                stats.track_start,
+                stats.decoder_error,
+                stats.unrecoverable_loss
+            ),
+            (2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn fir_sequence_numbers_increase() {
+        let requester = KeyframeRequester::new(KeyframeRequestPolicy {
+            use_fir: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        for (i, at) in [0, 2].into_iter().enumerate() {
+            requester.request(5, KeyframeReason::TrackStart);
+            let packet = requester.take(5, now + Duration::from_secs(at)).unwrap();
+            let fir = packet.as_any().downcast_ref::<FullIntraRequest>().unwrap();
+            assert_eq!(fir.fir[0].sequence_number, i as u8 + 1);
+        }
+        assert_eq!(requester.stats().fir_sent, 2);
+    }
+}
@@ -1,18 +1,60 @@
-use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::{Arc, OnceLock};
 
+use auth::Authenticator;
+use certificate::Fingerprint;
+use consent::ConsentGate;
+use simulcast::LayerTrack;
 use webrtc::{
-    peer_connection::RTCPeerConnection,
+    data_channel::RTCDataChannel, peer_connection::RTCPeerConnection,
+    rtp_transceiver::rtp_sender::RTCRtpSender,
     track::track_local::track_local_static_sample::TrackLocalStaticSample,
 };
 
+pub mod audio;
+pub mod auth;
 pub mod broad_cast;
+pub mod capture;
+pub mod certificate;
 pub mod client;
+pub mod clipboard;
+pub mod consent;
+pub mod cursor;
+pub mod damage;
+pub mod encoder;
+pub mod file_transfer;
+pub mod frame_bus;
+pub mod frame_source;
+pub mod input;
+pub mod jitter_buffer;
+pub mod keyframe_request;
+pub mod messaging;
+pub mod metrics;
 pub mod model;
+pub mod playback;
+pub mod rate_control;
 pub mod screen_capture;
 pub mod sdp;
+pub mod simulcast;
+pub mod stats;
+pub mod timing;
 pub const CLIENT_SDP_OFFER: &str = "client_sdp_offer";
 pub static RTC_CONFIG: OnceLock<Arc<RTCPeerConnection>> = OnceLock::new();
 pub static RTC_TRACK: OnceLock<Arc<TrackLocalStaticSample>> = OnceLock::new();
+/// Opus track, when the session was initialized with audio.
+pub static RTC_AUDIO_TRACK: OnceLock<Arc<TrackLocalStaticSample>> = OnceLock::new();
+/// Every simulcast layer, when the screen is sent with simulcast.
+pub static RTC_LAYER_TRACKS: OnceLock<Vec<LayerTrack>> = OnceLock::new();
+pub static RTC_SENDER: OnceLock<Arc<RTCRtpSender>> = OnceLock::new();
+pub static RTC_CURSOR_CHANNEL: OnceLock<Arc<RTCDataChannel>> = OnceLock::new();
+pub static RTC_CONTROL_CHANNEL: OnceLock<Arc<RTCDataChannel>> = OnceLock::new();
+pub static RTC_CLIPBOARD_CHANNEL: OnceLock<Arc<RTCDataChannel>> = OnceLock::new();
+pub static RTC_FILE_CHANNEL: OnceLock<Arc<RTCDataChannel>> = OnceLock::new();
+/// Verifies signaling tokens once authentication is enabled.
+pub static RTC_AUTHENTICATOR: OnceLock<Authenticator> = OnceLock::new();
+/// Asks the local user before a viewer is answered, once consent is enabled.
+pub static RTC_CONSENT: OnceLock<ConsentGate> = OnceLock::new();
+/// The only DTLS fingerprint a remote offer or answer is accepted with, once pinned.
+pub static RTC_REMOTE_FINGERPRINT: OnceLock<Fingerprint> = OnceLock::new();
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
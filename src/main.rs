@@ -1,8 +1,4 @@
-pub mod broad_cast;
-pub mod client;
-pub mod screen_capture;
-
 #[tokio::main]
 async fn main() {
-    // _ = run_client().await;
+    // _ = webrtc_client::client::run_client().await;
 }
@@ -0,0 +1,361 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use webrtc::data_channel::{
+    data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage, RTCDataChannel,
+};
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::{
+    auth::{self, Role},
+    consent::sharing_allowed,
+};
+
+/// Delivery guarantees of a [`MessageChannel`]; the default is reliable and ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelOptions {
+    pub ordered: bool,
+    /// Give up on a message after this many retransmissions.
+    pub max_retransmits: Option<u16>,
+    /// Give up on a message after this many milliseconds; exclusive with `max_retransmits`.
+    pub max_packet_life_time: Option<u16>,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        ChannelOptions {
+            ordered: true,
+            max_retransmits: None,
+            max_packet_life_time: None,
+        }
+    }
+}
+
+impl ChannelOptions {
+    fn init(&self) -> RTCDataChannelInit {
+        RTCDataChannelInit {
+            ordered: Some(self.ordered),
+            max_retransmits: self.max_retransmits,
+            max_packet_life_time: self.max_packet_life_time,
+            ..Default::default()
+        }
+    }
+}
+
+/// What goes over the wire, as JSON text messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Envelope {
+    Request {
+        id: u64,
+        method: String,
+        payload: Value,
+    },
+    /// Answer to the request with the same `id`.
+    Response {
+        id: u64,
+        payload: Value,
+    },
+    /// The request with the same `id` failed.
+    Error {
+        id: u64,
+        message: String,
+    },
+    Publish {
+        topic: String,
+        payload: Value,
+    },
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = Result<Value>> + Send>>;
+type RequestHandler = Arc<dyn Fn(Value) -> HandlerFuture + Send + Sync>;
+/// Returns `false` once the subscriber is gone.
+type Subscriber = Box<dyn Fn(&Value) -> bool + Send + Sync>;
+
+struct Inner {
+    channel: Arc<RTCDataChannel>,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Value, String>>>>,
+    handlers: Mutex<HashMap<String, RequestHandler>>,
+    subscribers: Mutex<HashMap<String, Vec<Subscriber>>>,
+}
+
+/// Typed request/response and publish/subscribe over one data channel.
+///
+/// Requests carry a correlation id that the response echoes, so any number can be in flight.
+/// Clones share the same channel, handlers and subscriptions.
+#[derive(Clone)]
+pub struct MessageChannel {
+    inner: Arc<Inner>,
+}
+
+impl MessageChannel {
+    /// Create a data channel labelled `label` on `peer_connection` and message over it.
+    pub async fn open(
+        peer_connection: &RTCPeerConnection,
+        label: &str,
+        options: ChannelOptions,
+    ) -> Result<Self> {
+        let channel = peer_connection
+            .create_data_channel(label, Some(options.init()))
+            .await?;
+        Ok(MessageChannel::attach(channel))
+    }
+
+    /// Message over `channel`, such as one the peer created.
+    pub fn attach(channel: Arc<RTCDataChannel>) -> Self {
+        let inner = Arc::new(Inner {
+            channel: channel.clone(),
+            next_id: AtomicU64::new(1),
+            pending: Mutex::default(),
+            handlers: Mutex::default(),
+            subscribers: Mutex::default(),
+        });
+        let weak = Arc::downgrade(&inner);
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let weak = weak.clone();
+            Box::pin(async move {
+                if !message.is_string {
+                    return;
+                }
+                match serde_json::from_slice::<Envelope>(&message.data) {
+                    Ok(envelope) => dispatch(weak, envelope).await,
+                    Err(e) => eprintln!("Error parsing message {}", e),
+                }
+            })
+        }));
+        MessageChannel { inner }
+    }
+
+    pub fn channel(&self) -> &Arc<RTCDataChannel> {
+        &self.inner.channel
+    }
+
+    /// Call `method` on the peer and wait up to `timeout` for its response.
+    pub async fn request<Req, Resp>(
+        &self,
+        method: &str,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(id, tx);
+        let envelope = Envelope::Request {
+            id,
+            method: method.to_string(),
+            payload: serde_json::to_value(request)?,
+        };
+        if let Err(e) = send(&self.inner.channel, &envelope).await {
+            self.inner.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        let response = tokio::time::timeout(timeout, rx).await;
+        self.inner.pending.lock().unwrap().remove(&id);
+        match response {
+            Ok(Ok(Ok(payload))) => Ok(serde_json::from_value(payload)?),
+            Ok(Ok(Err(message))) => bail!("{} failed: {}", method, message),
+            Ok(Err(_)) => bail!("{} was dropped", method),
+            Err(_) => bail!("{} timed out after {:?}", method, timeout),
+        }
+    }
+
+    /// Answer the peer's `method` requests with `handler`; replaces an earlier handler.
+    /// Requests are only handled for sessions with [`Role::Control`].
+    pub fn on_request<Req, Resp, F, Fut>(&self, method: &str, handler: F)
+    where
+        Req: DeserializeOwned,
+        Resp: Serialize,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Resp>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let erased: RequestHandler = Arc::new(move |payload: Value| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let request = serde_json::from_value(payload)?;
+                Ok(serde_json::to_value(handler(request).await?)?)
+            })
+        });
+        self.inner
+            .handlers
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), erased);
+    }
+
+    pub async fn publish<T: Serialize>(&self, topic: &str, message: &T) -> Result<()> {
+        let envelope = Envelope::Publish {
+            topic: topic.to_string(),
+            payload: serde_json::to_value(message)?,
+        };
+        send(&self.inner.channel, &envelope).await
+    }
+
+    /// Messages the peer publishes on `topic`, until the receiver is dropped.
+    pub fn subscribe<T>(&self, topic: &str) -> mpsc::UnboundedReceiver<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let subscriber: Subscriber = Box::new(
+            move |payload: &Value| match serde_json::from_value::<T>(payload.clone()) {
+                Ok(message) => tx.send(message).is_ok(),
+                Err(e) => {
+                    eprintln!("Error parsing published message {}", e);
+                    !tx.is_closed()
+                }
+            },
+        );
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .entry(topic.to_string())
+            .or_default()
+            .push(subscriber);
+        rx
+    }
+}
+
+async fn send(channel: &RTCDataChannel, envelope: &Envelope) -> Result<()> {
+    if !sharing_allowed() {
+        bail!("Sharing with the viewer was revoked");
+    }
+    channel.send_text(serde_json::to_string(envelope)?).await?;
+    Ok(())
+}
+
+async fn dispatch(weak: Weak<Inner>, envelope: Envelope) {
+    let Some(inner) = weak.upgrade() else {
+        return;
+    };
+    match envelope {
+        Envelope::Request {
+            id,
+            method,
+            payload,
+        } => {
+            let handler = inner.handlers.lock().unwrap().get(&method).cloned();
+            let channel = inner.channel.clone();
+            // A slow handler must not hold up the messages behind it.
+            tokio::spawn(async move {
+                let result = match handler {
+                    // Handlers may do anything, so viewers who may only watch get none.
+                    Some(_) if !auth::permits(Role::Control) => {
+                        Err(anyhow!("{} needs the control role", method))
+                    }
+                    Some(handler) => handler(payload).await,
+                    None => Err(anyhow!("No handler for {}", method)),
+                };
+                let reply = match result {
+                    Ok(payload) => Envelope::Response { id, payload },
+                    Err(e) => Envelope::Error {
+                        id,
+                        message: e.to_string(),
+                    },
+                };
+                if let Err(e) = send(&channel, &reply).await {
+                    eprintln!("Error sending response {}", e);
+                }
+            });
+        }
+        Envelope::Response { id, payload } => {
+            if let Some(tx) = inner.pending.lock().unwrap().remove(&id) {
+                let _ = tx.send(Ok(payload));
+            }
+        }
+        Envelope::Error { id, message } => {
+            if let Some(tx) = inner.pending.lock().unwrap().remove(&id) {
+                let _ = tx.send(Err(message));
+            }
+        }
+        Envelope::Publish { topic, payload } => {
+            let mut subscribers = inner.subscribers.lock().unwrap();
+            if let Some(subscribers) = subscribers.get_mut(&topic) {
+                subscribers.retain(|subscriber| subscriber(&payload));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::api::APIBuilder;
+    use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+
+    async fn connect(offerer: &RTCPeerConnection, answerer: &RTCPeerConnection) {
+        for (local, remote, offer) in [(offerer, answerer, true), (answerer, offerer, false)] {
+            let description = if offer {
+                local.create_offer(None).await.unwrap()
+            } else {
+                local.create_answer(None).await.unwrap()
+            };
+            let mut gathered = local.gathering_complete_promise().await;
+            local.set_local_description(description).await.unwrap();
+            gathered.recv().await;
+            let description = local.local_description().await.unwrap();
+            remote.set_remote_description(description).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_and_publications_cross_a_real_channel() {
+        let api = APIBuilder::new().build();
+        let admin = api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let client = api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let (channel_tx, mut channel_rx) = mpsc::unbounded_channel();
+        client.on_data_channel(Box::new(move |channel| {
+            let _ = channel_tx.send(channel);
+            Box::pin(async {})
+        }));
+        let local = MessageChannel::open(&admin, "rpc", ChannelOptions::default())
+            .await
+            .unwrap();
+        connect(&admin, &client).await;
+
+        let remote = tokio::time::timeout(Duration::from_secs(10), async {
+            let remote = MessageChannel::attach(channel_rx.recv().await.unwrap());
+            while local.channel().ready_state() != RTCDataChannelState::Open {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            remote
+        })
+        .await
+        .unwrap();
+        remote.on_request("add", |(a, b): (i32, i32)| async move { Ok(a + b) });
+        let mut status = local.subscribe::<String>("status");
+
+        let timeout = Duration::from_secs(5);
+        let sum: i32 = local.request("add", &(2, 3), timeout).await.unwrap();
+        assert_eq!(sum, 5);
+        let missing = local.request::<_, i32>("multiply", &(2, 3), timeout).await;
+        assert!(missing.unwrap_err().to_string().contains("No handler"));
+
+        remote.publish("status", &"busy").await.unwrap();
+        let published = tokio::time::timeout(timeout, status.recv()).await.unwrap();
+        assert_eq!(published.as_deref(), Some("busy"));
+
+        admin.close().await.unwrap();
+        client.close().await.unwrap();
+    }
+}
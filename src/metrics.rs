@@ -0,0 +1,381 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::frame_bus::FrameBus;
+use crate::jitter_buffer::JitterBufferStats;
+use crate::stats::{ConnectionType, StatsCollector};
+
+/// Reads one counter out of an incoming track's stats.
+type ReceiveCounter = fn(&JitterBufferStats) -> u64;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Metrics of this process, fed by the capture threads and registered peer connections.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+/// Upper bounds in seconds of the encode time histogram buckets.
+const ENCODE_BUCKETS: [f64; 8] = [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.25];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; ENCODE_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(ENCODE_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+struct FpsMeter {
+    window_start: Instant,
+    frames: u64,
+    fps: f64,
+}
+
+impl Default for FpsMeter {
+    fn default() -> Self {
+        FpsMeter {
+            window_start: Instant::now(),
+            frames: 0,
+            fps: 0.0,
+        }
+    }
+}
+
+/// Process-wide metrics, rendered in the Prometheus text format by [`serve_metrics`].
+#[derive(Default)]
+pub struct Metrics {
+    frames_captured: AtomicU64,
+    capture_fps: Mutex<FpsMeter>,
+    encode_time: Histogram,
+    frame_bus: Mutex<Option<FrameBus>>,
+    /// Reassembly counters of every incoming video track, by SSRC.
+    receive: Mutex<BTreeMap<u32, JitterBufferStats>>,
+    sessions: Mutex<BTreeMap<String, Arc<RTCPeerConnection>>>,
+}
+
+impl Metrics {
+    /// Count a frame published by the capture loop.
+    pub fn record_frame_captured(&self) {
+        self.frames_captured.fetch_add(1, Ordering::Relaxed);
+        let mut meter = self.capture_fps.lock().unwrap();
+        meter.frames += 1;
+        let elapsed = meter.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            meter.fps = meter.frames as f64 / elapsed.as_secs_f64();
+            meter.frames = 0;
+            meter.window_start = Instant::now();
+        }
+    }
+
+    pub fn record_encode_time(&self, elapsed: Duration) {
+        self.encode_time.observe(elapsed);
+    }
+
+    /// Report drops of the subscribers of `bus`; `None` once the capture stops.
+    pub fn set_frame_bus(&self, bus: Option<FrameBus>) {
+        if bus.is_none() {
+            *self.capture_fps.lock().unwrap() = FpsMeter::default();
+        }
+        *self.frame_bus.lock().unwrap() = bus;
+    }
+
+    /// Report the reassembly counters of the incoming track `ssrc`; `None` once it ends.
+    pub fn set_receive_stats(&self, ssrc: u32, stats: Option<JitterBufferStats>) {
+        let mut receive = self.receive.lock().unwrap();
+        match stats {
+            Some(stats) => receive.insert(ssrc, stats),
+            None => receive.remove(&ssrc),
+        };
+    }
+
+    /// Report bytes transferred, ICE state and TURN usage of `peer_connection` under `session`.
+    pub fn add_session(&self, session: &str, peer_connection: Arc<RTCPeerConnection>) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session.to_string(), peer_connection);
+    }
+
+    pub fn remove_session(&self, session: &str) {
+        self.sessions.lock().unwrap().remove(session);
+    }
+
+    /// Current metrics in the Prometheus text exposition format.
+    pub async fn render(&self) -> String {
+        let mut out = String::new();
+        metric(
+            &mut out,
+            "frames_captured_total",
+            "counter",
+            "Frames published by the capture loop.",
+        );
+        let _ = writeln!(
+            out,
+            "webrtc_client_frames_captured_total {}",
+            self.frames_captured.load(Ordering::Relaxed)
+        );
+        metric(
+            &mut out,
+            "capture_fps",
+            "gauge",
+            "Frames per second published by the capture loop.",
+        );
+        let _ = writeln!(
+            out,
+            "webrtc_client_capture_fps {}",
+            self.capture_fps.lock().unwrap().fps
+        );
+
+        metric(
+            &mut out,
+            "frames_dropped_total",
+            "counter",
+            "Frames a frame bus subscriber skipped because it fell behind.",
+        );
+        let bus = self.frame_bus.lock().unwrap().clone();
+        for stats in bus.map(|b| b.subscriber_stats()).unwrap_or_default() {
+            let _ = writeln!(
+                out,
+                "webrtc_client_frames_dropped_total{{subscriber=\"{}\"}} {}",
+                escape(&stats.name),
+                stats.dropped
+            );
+        }
+
+        metric(
+            &mut out,
+            "encode_seconds",
+            "histogram",
+            "Time spent encoding one frame.",
+        );
+        let histogram = &self.encode_time;
+        for (bucket, bound) in histogram.buckets.iter().zip(ENCODE_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "webrtc_client_encode_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = histogram.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "webrtc_client_encode_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "webrtc_client_encode_seconds_sum {}",
+            histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "webrtc_client_encode_seconds_count {count}");
+
+        let receive = self.receive.lock().unwrap().clone();
+        let receive_counters: [(&str, &str, ReceiveCounter); 2] = [
+            (
+                "rtp_packets_late_total",
+                "Packets of an incoming track that arrived after their frame was done with.",
+                |stats| stats.late,
+            ),
+            (
+                "rtp_packets_lost_total",
+                "Packets of an incoming track that NACK did not recover in time.",
+                |stats| stats.lost,
+            ),
+        ];
+        for (name, help, counter) in receive_counters {
+            metric(&mut out, name, "counter", help);
+            for (ssrc, stats) in &receive {
+                let _ = writeln!(
+                    out,
+                    "webrtc_client_{name}{{ssrc=\"{ssrc}\"}} {}",
+                    counter(stats)
+                );
+            }
+        }
+
+        // Scrapes only read counters, so concurrent scrapers cannot skew each other.
+        let sessions = self.sessions.lock().unwrap().clone();
+        metric(
+            &mut out,
+            "active_sessions",
+            "gauge",
+            "Peer connections being reported on.",
+        );
+        let _ = writeln!(out, "webrtc_client_active_sessions {}", sessions.len());
+        let mut lines = vec![];
+        for (session, peer_connection) in sessions {
+            let state = peer_connection.ice_connection_state();
+            let stats = StatsCollector::new(peer_connection).snapshot().await;
+            let session = escape(&session);
+            let relayed = stats.connection == Some(ConnectionType::Relay);
+            lines.push((
+                "session_sent_bytes_total",
+                format!("{{session=\"{session}\"}} {}", stats.bytes_sent),
+            ));
+            lines.push((
+                "session_received_bytes_total",
+                format!("{{session=\"{session}\"}} {}", stats.bytes_received),
+            ));
+            lines.push((
+                "session_ice_connection_state",
+                format!("{{session=\"{session}\",state=\"{state}\"}} 1"),
+            ));
+            lines.push((
+                "session_relayed",
+                format!("{{session=\"{session}\"}} {}", relayed as u8),
+            ));
+        }
+        for (name, kind, help) in [
+            (
+                "session_sent_bytes_total",
+                "counter",
+                "RTP bytes sent over the session; take rate() for the bitrate.",
+            ),
+            (
+                "session_received_bytes_total",
+                "counter",
+                "RTP bytes received over the session; take rate() for the bitrate.",
+            ),
+            (
+                "session_ice_connection_state",
+                "gauge",
+                "Current ICE connection state of the session.",
+            ),
+            (
+                "session_relayed",
+                "gauge",
+                "Whether the selected candidate pair goes through TURN.",
+            ),
+        ] {
+            metric(&mut out, name, kind, help);
+            for (_, line) in lines.iter().filter(|(n, _)| *n == name) {
+                let _ = writeln!(out, "webrtc_client_{name}{line}");
+            }
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP webrtc_client_{name} {help}");
+    let _ = writeln!(out, "# TYPE webrtc_client_{name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve [`metrics`] at `GET /metrics` on `addr`; returns the address actually bound.
+pub async fn serve_metrics(addr: SocketAddr) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let bound = listener.local_addr()?;
+    println!("Serving metrics on http://{}/metrics", bound);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_scrape(stream).await {
+                            eprintln!("Error serving metrics {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Error accepting metrics connection {}", e),
+            }
+        }
+    });
+    Ok(bound)
+}
+
+async fn handle_scrape(mut stream: TcpStream) -> Result<()> {
+    let mut request = vec![0u8; 1024];
+    let n = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..n]);
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        ("200 OK", metrics().render().await)
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn render_includes_histogram_and_drops() {
+        let metrics = Metrics::default();
+        metrics.record_encode_time(Duration::from_millis(3));
+        metrics.record_frame_captured();
+        let bus = FrameBus::new();
+        let _track = bus.subscribe("track");
+        metrics.set_frame_bus(Some(bus));
+        let stats = JitterBufferStats {
+            lost: 3,
+            ..Default::default()
+        };
+        metrics.set_receive_stats(42, Some(stats));
+        let pc = webrtc::api::APIBuilder::new()
+            .build()
+            .new_peer_connection(Default::default())
+            .await
+            .unwrap();
+        metrics.add_session("viewer", Arc::new(pc));
+
+        let text = metrics.render().await;
+        assert!(text.contains("webrtc_client_encode_seconds_bucket{le=\"0.002\"} 0"));
+        assert!(text.contains("webrtc_client_encode_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(text.contains("webrtc_client_encode_seconds_count 1"));
+        assert!(text.contains("webrtc_client_frames_dropped_total{subscriber=\"track\"} 0"));
+        assert!(text.contains("webrtc_client_active_sessions 1"));
+        assert!(text.contains("webrtc_client_session_sent_bytes_total{session=\"viewer\"} 0"));
+        assert!(text.contains("webrtc_client_rtp_packets_lost_total{ssrc=\"42\"} 3"));
+    }
+
+    #[tokio::test]
+    async fn endpoint_serves_metrics() {
+        let addr = serve_metrics("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("# TYPE webrtc_client_capture_fps gauge"));
+    }
+}
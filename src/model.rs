@@ -15,6 +15,9 @@ pub struct SdpOfferAnswer {
     pub answer: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub client_id: Option<String>,
+    /// Signed token vouching for `client_id`, see [`crate::auth::Authenticator`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
 }
 
 impl SdpOfferAnswer {
@@ -24,9 +27,15 @@ impl SdpOfferAnswer {
             offer,
             answer,
             client_id,
+            token: None,
         }
     }
 
+    pub fn with_token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
     pub fn to_ws(&self) -> Message {
         Message::text(serde_json::to_string(&self).unwrap())
     }
@@ -0,0 +1,562 @@
+use anyhow::{bail, Context, Result};
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals, SampleRate};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use webrtc::rtp::codecs::opus::OpusPacket;
+use webrtc::rtp::packetizer::Depacketizer;
+use webrtc::track::track_remote::TrackRemote;
+
+use crate::audio::{AudioFrame, CHANNELS, FRAME_DURATION, FRAME_SAMPLES, SAMPLE_RATE};
+
+/// Longest Opus packet, 120 ms, in samples per channel.
+const MAX_DECODED_SAMPLES: usize = FRAME_SAMPLES * 6;
+
+type SinkFactory = Arc<dyn Fn() -> Result<Box<dyn AudioSink>> + Send + Sync>;
+
+static SINK_FACTORY: Mutex<Option<SinkFactory>> = Mutex::new(None);
+
+/// Play incoming audio tracks into sinks built by `factory`, one per track.
+pub fn set_sink_factory<F>(factory: F)
+where
+    F: Fn() -> Result<Box<dyn AudioSink>> + Send + Sync + 'static,
+{
+    *SINK_FACTORY.lock().unwrap() = Some(Arc::new(factory));
+}
+
+/// Where decoded remote audio goes.
+pub trait AudioSink: Send {
+    fn play(&mut self, frame: &AudioFrame) -> Result<()>;
+}
+
+/// Writes what it plays into a 16-bit PCM WAV file, for tests and recordings.
+///
+/// The header is completed by [`WavSink::finish`] or when the sink is dropped.
+pub struct WavSink {
+    writer: BufWriter<File>,
+    channels: u16,
+    data_len: u32,
+}
+
+impl WavSink {
+    pub fn create(path: impl AsRef<Path>, channels: u16) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).with_context(|| format!("Failed to create {:?}", path))?;
+        let mut sink = WavSink {
+            writer: BufWriter::new(file),
+            channels,
+            data_len: 0,
+        };
+        sink.write_header()?;
+        Ok(sink)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let block_align = self.channels * 2;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + self.data_len).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?;
+        w.write_all(&self.channels.to_le_bytes())?;
+        w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&16u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&self.data_len.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Fill in the sizes in the header; more frames may still be played afterwards.
+    pub fn finish(&mut self) -> Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn play(&mut self, frame: &AudioFrame) -> Result<()> {
+        if frame.channels != self.channels {
+            bail!(
+                "Cannot write {} channels into a {} channel WAV file",
+                frame.channels,
+                self.channels
+            );
+        }
+        for sample in &frame.samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += frame.samples.len() as u32 * 2;
+        Ok(())
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Error finishing WAV file {}", e);
+        }
+    }
+}
+
+/// Plays through `pacat` (PulseAudio) or `pw-play` (PipeWire).
+pub struct SystemAudioSink {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl SystemAudioSink {
+    pub fn open() -> Result<Self> {
+        let rate = SAMPLE_RATE.to_string();
+        let channels = CHANNELS.to_string();
+        let candidates: [(&str, Vec<&str>); 2] = [
+            (
+                "pacat",
+                vec![
+                    "--playback",
+                    "--format=s16le",
+                    "--rate",
+                    &rate,
+                    "--channels",
+                    &channels,
+                    "--raw",
+                ],
+            ),
+            (
+                "pw-play",
+                vec![
+                    "--format",
+                    "s16",
+                    "--rate",
+                    &rate,
+                    "--channels",
+                    &channels,
+                    "-",
+                ],
+            ),
+        ];
+        for (program, args) in candidates {
+            let Ok(mut child) = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            else {
+                continue;
+            };
+            if let Some(stdin) = child.stdin.take() {
+                println!("Playing remote audio with {}", program);
+                return Ok(SystemAudioSink { child, stdin });
+            }
+            let _ = child.kill();
+        }
+        bail!("Neither pacat nor pw-play is available to play audio")
+    }
+}
+
+impl AudioSink for SystemAudioSink {
+    fn play(&mut self, frame: &AudioFrame) -> Result<()> {
+        let bytes: Vec<u8> = frame.samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        self.stdin.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+impl Drop for SystemAudioSink {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Turns depacketized payloads back into PCM.
+pub trait AudioDecoder: Send {
+    fn decode(&mut self, payload: &[u8]) -> Result<AudioFrame>;
+
+    /// Stand-in for a frame that was lost; `next` is the packet after it, if it arrived.
+    fn conceal(&mut self, next: Option<&[u8]>) -> AudioFrame;
+}
+
+/// Decodes Opus into [`CHANNELS`]. A loss is recovered from the in-band FEC of the next
+/// packet when there is one, and otherwise concealed by Opus' own packet loss concealment.
+pub struct OpusDecoder {
+    decoder: Decoder,
+}
+
+impl OpusDecoder {
+    pub fn new() -> Result<Self> {
+        Ok(OpusDecoder {
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Stereo)?,
+        })
+    }
+
+    fn decode_into(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: usize,
+        fec: bool,
+    ) -> Result<AudioFrame> {
+        let packet = packet.map(Packet::try_from).transpose()?;
+        let mut pcm = vec![0; samples * CHANNELS as usize];
+        let decoded = self
+            .decoder
+            .decode(packet, MutSignals::try_from(&mut pcm)?, fec)?;
+        pcm.truncate(decoded * CHANNELS as usize);
+        Ok(AudioFrame::new(pcm, CHANNELS))
+    }
+}
+
+impl AudioDecoder for OpusDecoder {
+    fn decode(&mut self, payload: &[u8]) -> Result<AudioFrame> {
+        self.decode_into(Some(payload), MAX_DECODED_SAMPLES, false)
+    }
+
+    fn conceal(&mut self, next: Option<&[u8]>) -> AudioFrame {
+        // Decoding FEC asks for exactly the lost frame's duration.
+        let recovered = match next {
+            Some(next) => self.decode_into(Some(next), FRAME_SAMPLES, true),
+            None => self.decode_into(None, FRAME_SAMPLES, false),
+        };
+        recovered.unwrap_or_else(|e| {
+            eprintln!("Error concealing lost audio {}", e);
+            AudioFrame::new(vec![0; FRAME_SAMPLES * CHANNELS as usize], CHANNELS)
+        })
+    }
+}
+
+/// How much audio is buffered against network jitter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitterConfig {
+    /// Buffered before playout starts, and again after running dry.
+    pub target_delay: Duration,
+    /// Beyond this the oldest packets are dropped to catch up.
+    pub max_delay: Duration,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        JitterConfig {
+            target_delay: Duration::from_millis(60),
+            max_delay: Duration::from_millis(300),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitterStats {
+    pub received: u64,
+    /// Arrived after their turn to play had passed, or twice.
+    pub late: u64,
+    /// Missing when their turn came, and concealed.
+    pub lost: u64,
+    /// Dropped to bring the delay back under the maximum.
+    pub dropped: u64,
+}
+
+/// What to play next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    Packet(Bytes),
+    Lost,
+}
+
+/// Reorders audio packets by sequence number and releases one per frame interval.
+///
+/// Assumes packets of [`FRAME_DURATION`], which is what browsers send.
+#[derive(Debug)]
+pub struct AudioJitterBuffer {
+    target_packets: usize,
+    max_packets: usize,
+    /// Sequence number of `slots[0]`.
+    next: Option<u16>,
+    slots: VecDeque<Option<Bytes>>,
+    playing: bool,
+    stats: JitterStats,
+}
+
+impl AudioJitterBuffer {
+    pub fn new(config: JitterConfig) -> Self {
+        let packets = |delay: Duration| (delay.as_millis() / FRAME_DURATION.as_millis()) as usize;
+        let target_packets = packets(config.target_delay).max(1);
+        AudioJitterBuffer {
+            target_packets,
+            max_packets: packets(config.max_delay).max(target_packets + 1),
+            next: None,
+            slots: VecDeque::new(),
+            playing: false,
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    pub fn push(&mut self, sequence: u16, payload: Bytes) {
+        self.stats.received += 1;
+        let next = *self.next.get_or_insert(sequence);
+        let offset = sequence.wrapping_sub(next) as i16;
+        if offset < 0 || self.slots.get(offset as usize).is_some_and(|s| s.is_some()) {
+            self.stats.late += 1;
+            return;
+        }
+        let offset = offset as usize;
+        if offset >= self.slots.len() {
+            self.slots.resize(offset + 1, None);
+        }
+        self.slots[offset] = Some(payload);
+        while self.slots.len() > self.max_packets {
+            if self.advance().is_some() {
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    /// Next frame to play, or `None` while buffering.
+    pub fn pop(&mut self) -> Option<Playout> {
+        if !self.playing {
+            if self.slots.len() < self.target_packets {
+                return None;
+            }
+            self.playing = true;
+        }
+        if self.slots.is_empty() {
+            // Ran dry: build the delay back up rather than conceal indefinitely.
+            self.playing = false;
+            return None;
+        }
+        Some(match self.advance() {
+            Some(payload) => Playout::Packet(payload),
+            None => {
+                self.stats.lost += 1;
+                Playout::Lost
+            }
+        })
+    }
+
+    /// The packet that plays next, if it has arrived.
+    pub fn peek(&self) -> Option<&Bytes> {
+        self.slots.front().and_then(|slot| slot.as_ref())
+    }
+
+    fn advance(&mut self) -> Option<Bytes> {
+        let slot = self.slots.pop_front().flatten();
+        self.next = self.next.map(|next| next.wrapping_add(1));
+        slot
+    }
+}
+
+/// Decodes what the jitter buffer releases into a sink.
+pub struct AudioPlayer {
+    buffer: AudioJitterBuffer,
+    depacketizer: OpusPacket,
+    decoder: Box<dyn AudioDecoder>,
+    sink: Box<dyn AudioSink>,
+}
+
+impl AudioPlayer {
+    pub fn new(
+        config: JitterConfig,
+        decoder: Box<dyn AudioDecoder>,
+        sink: Box<dyn AudioSink>,
+    ) -> Self {
+        AudioPlayer {
+            buffer: AudioJitterBuffer::new(config),
+            depacketizer: OpusPacket,
+            decoder,
+            sink,
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.buffer.stats()
+    }
+
+    /// Buffer the RTP payload with sequence number `sequence`.
+    pub fn receive(&mut self, sequence: u16, payload: &Bytes) -> Result<()> {
+        let payload = self.depacketizer.depacketize(payload)?;
+        self.buffer.push(sequence, payload);
+        Ok(())
+    }
+
+    /// Play one frame interval; returns whether anything was played.
+    pub fn tick(&mut self) -> Result<bool> {
+        let frame = match self.buffer.pop() {
+            None => return Ok(false),
+            Some(Playout::Packet(payload)) => match self.decoder.decode(&payload) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    eprintln!("Error decoding audio {}", e);
+                    self.decoder.conceal(None)
+                }
+            },
+            Some(Playout::Lost) => {
+                let next = self.buffer.peek().cloned();
+                self.decoder.conceal(next.as_deref())
+            }
+        };
+        self.sink.play(&frame)?;
+        Ok(true)
+    }
+}
+
+/// Play `track` through `player` until the track ends; the returned stats follow along.
+pub fn spawn_playback(track: Arc<TrackRemote>, mut player: AudioPlayer) -> Arc<Mutex<JitterStats>> {
+    let stats = Arc::new(Mutex::new(JitterStats::default()));
+    let (packet_tx, mut packet_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((rtp, _)) = track.read_rtp().await {
+            if packet_tx.send(rtp).is_err() {
+                break;
+            }
+        }
+    });
+    let shared = stats.clone();
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(FRAME_DURATION);
+        let mut open = true;
+        loop {
+            tokio::select! {
+                rtp = packet_rx.recv(), if open => match rtp {
+                    Some(rtp) => {
+                        if let Err(e) = player.receive(rtp.header.sequence_number, &rtp.payload) {
+                            eprintln!("Error depacketizing audio {}", e);
+                        }
+                    }
+                    None => open = false,
+                },
+                _ = ticks.tick() => {
+                    let played = match player.tick() {
+                        Ok(played) => played,
+                        Err(e) => {
+                            eprintln!("Error playing audio {}", e);
+                            break;
+                        }
+                    };
+                    *shared.lock().unwrap() = player.stats();
+                    if !played && !open {
+                        break;
+                    }
+                }
+            }
+        }
+        println!("Remote audio ended, {:?}", player.stats());
+    });
+    stats
+}
+
+/// Play `track` into a sink from the factory set with [`set_sink_factory`], if there is one.
+pub(crate) fn play_remote_track(track: Arc<TrackRemote>) {
+    let Some(factory) = SINK_FACTORY.lock().unwrap().clone() else {
+        println!("No audio sink set, ignoring audio track {}", track.ssrc());
+        return;
+    };
+    let decoder = match OpusDecoder::new() {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            eprintln!("Error creating audio decoder {}", e);
+            return;
+        }
+    };
+    match factory() {
+        Ok(sink) => {
+            let player = AudioPlayer::new(JitterConfig::default(), Box::new(decoder), sink);
+            spawn_playback(track, player);
+        }
+        Err(e) => eprintln!("Error opening audio sink {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioEncoder, AudioSource, OpusEncoder, SineSource, WavSource};
+
+    #[test]
+    fn jitter_buffer_reorders_and_counts_late_and_lost() {
+        let mut buffer = AudioJitterBuffer::new(JitterConfig::default());
+        let packet = |n: u8| Bytes::from(vec![n]);
+        buffer.push(65534, packet(1));
+        assert_eq!(buffer.pop(), None);
+        // Reordered across the wrap; 65535 arrives too late.
+        buffer.push(1, packet(4));
+        buffer.push(0, packet(3));
+        assert_eq!(buffer.pop(), Some(Playout::Packet(packet(1))));
+        assert_eq!(buffer.pop(), Some(Playout::Lost));
+        buffer.push(65535, packet(2));
+        buffer.push(0, packet(3));
+        assert_eq!(buffer.pop(), Some(Playout::Packet(packet(3))));
+        assert_eq!(buffer.pop(), Some(Playout::Packet(packet(4))));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(
+            buffer.stats(),
+            JitterStats {
+                received: 5,
+                late: 2,
+                lost: 1,
+                dropped: 0
+            }
+        );
+    }
+
+    #[test]
+    fn lost_packets_are_concealed_in_the_recording() {
+        let dir = std::env::temp_dir().join(format!("playback-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("remote.wav");
+        let sink = WavSink::create(&path, CHANNELS).unwrap();
+        let mut player = AudioPlayer::new(
+            JitterConfig::default(),
+            Box::new(OpusDecoder::new().unwrap()),
+            Box::new(sink),
+        );
+        let mut encoder = OpusEncoder::new().unwrap();
+        let mut tone = SineSource::new(440.0, 1);
+        let packets: Vec<Bytes> = (0..5)
+            .map(|_| {
+                encoder
+                    .encode(&tone.next_frame().unwrap().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        // Packet 11 never arrives.
+        for sequence in [10, 12, 13, 14] {
+            player
+                .receive(sequence, &packets[sequence as usize - 10])
+                .unwrap();
+        }
+        while player.tick().unwrap() {}
+        assert_eq!(player.stats().lost, 1);
+        drop(player);
+
+        let mut recording = WavSource::open(&path).unwrap();
+        let mut peaks = vec![];
+        while let Some(frame) = recording.next_frame().unwrap() {
+            peaks.push(
+                frame
+                    .samples
+                    .iter()
+                    .map(|s| s.unsigned_abs())
+                    .max()
+                    .unwrap(),
+            );
+        }
+        assert_eq!(peaks.len(), 5);
+        // The tone carries on through the lost frame instead of dropping out.
+        assert!(peaks[1..].iter().all(|peak| *peak > 1000), "{:?}", peaks);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,458 @@
+use image::imageops::FilterType;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webrtc::rtcp::{
+    packet::Packet,
+    payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    receiver_report::ReceiverReport,
+    transport_feedbacks::transport_layer_cc::{PacketStatusChunk, SymbolTypeTcc, TransportLayerCc},
+};
+
+use crate::client::DEFAULT_FPS;
+use crate::timing::FrameRate;
+
+/// Output height used when nothing constrains it.
+pub const DEFAULT_HEIGHT: u32 = 720;
+/// Highest output height of [`ContentHint::Detail`]; smaller displays are sent as they are.
+pub const DETAIL_MAX_HEIGHT: u32 = 2160;
+
+// Loss thresholds and step sizes of the loss-based controller in Google Congestion Control.
+const LOSS_DECREASE_THRESHOLD: f64 = 0.10;
+const LOSS_INCREASE_THRESHOLD: f64 = 0.02;
+const INCREASE_FACTOR: f64 = 1.08;
+const INCREASE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What gives way first when the bandwidth estimate drops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Degradation {
+    /// Lower the frame rate before the resolution, so text stays legible.
+    #[default]
+    MaintainResolution,
+    /// Lower the resolution before the frame rate, so motion stays smooth.
+    MaintainFrameRate,
+}
+
+/// Limits the [`RateController`] keeps the video settings within.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateBounds {
+    /// Bits per second.
+    pub min_bitrate: u32,
+    pub max_bitrate: u32,
+    /// Estimate used until the first feedback arrives.
+    pub start_bitrate: u32,
+    pub min_fps: f64,
+    pub max_fps: f64,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub degradation: Degradation,
+}
+
+impl Default for RateBounds {
+    fn default() -> Self {
+        RateBounds {
+            min_bitrate: 150_000,
+            max_bitrate: 4_000_000,
+            start_bitrate: 1_000_000,
+            min_fps: 5.0,
+            max_fps: DEFAULT_FPS,
+            min_height: 360,
+            max_height: DEFAULT_HEIGHT,
+            degradation: Degradation::default(),
+        }
+    }
+}
+
+impl RateBounds {
+    fn clamp_bitrate(&self, bitrate: f64) -> f64 {
+        bitrate.clamp(
+            self.min_bitrate as f64,
+            self.max_bitrate.max(self.min_bitrate) as f64,
+        )
+    }
+}
+
+/// Encoder bitrate, capture frame rate and output height for one bandwidth estimate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoSettings {
+    /// Target handed to the encoder; [`crate::encoder::PassthroughEncoder`] ignores it.
+    pub bitrate: u32,
+    pub frame_rate: f64,
+    pub height: u32,
+}
+
+impl VideoSettings {
+    /// Settings for `bitrate` within `bounds`.
+    ///
+    /// The upper half of the range (on a log scale) trades whatever `bounds.degradation`
+    /// gives up first, the lower half the other. Screen content stays legible at a low
+    /// frame rate but not at a low resolution, so by default the frame rate goes first.
+    pub fn for_bitrate(bitrate: u32, bounds: &RateBounds) -> Self {
+        let bitrate = bounds.clamp_bitrate(bitrate as f64);
+        let span = (bounds.max_bitrate as f64 / bounds.min_bitrate.max(1) as f64).ln();
+        let quality = if span > 0.0 {
+            ((bitrate / bounds.min_bitrate as f64).ln() / span).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        let (fps_quality, height_quality) = match bounds.degradation {
+            Degradation::MaintainResolution => (quality * 2.0 - 1.0, quality * 2.0),
+            Degradation::MaintainFrameRate => (quality * 2.0, quality * 2.0 - 1.0),
+        };
+        let frame_rate = lerp(bounds.min_fps, bounds.max_fps, fps_quality);
+        let height = lerp(
+            bounds.min_height as f64,
+            bounds.max_height as f64,
+            height_quality,
+        );
+        VideoSettings {
+            bitrate: bitrate as u32,
+            frame_rate,
+            // Even heights keep chroma subsampling in encoders happy.
+            height: (height as u32 & !1).max(2),
+        }
+    }
+}
+
+fn lerp(min: f64, max: f64, t: f64) -> f64 {
+    min + (max - min) * t.clamp(0.0, 1.0)
+}
+
+/// What the shared screen mostly shows, like the `contentHint` of a browser track.
+///
+/// The hint sets the frame rate, resolution and scale filter. It does not tune encoding yet:
+/// [`crate::encoder::PassthroughEncoder`] ignores it until a real encoder exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentHint {
+    /// Text, documents and UI: full resolution at a low frame rate.
+    Detail,
+    /// Video playback and animation: scaled down at a high frame rate.
+    Motion,
+}
+
+impl ContentHint {
+    /// `bounds` with the frame rate and resolution range of the content; the bitrate range
+    /// is kept.
+    pub fn bounds(self, bounds: RateBounds) -> RateBounds {
+        match self {
+            ContentHint::Detail => RateBounds {
+                min_fps: 2.0,
+                max_fps: 10.0,
+                min_height: DEFAULT_HEIGHT,
+                max_height: DETAIL_MAX_HEIGHT,
+                degradation: Degradation::MaintainResolution,
+                ..bounds
+            },
+            ContentHint::Motion => RateBounds {
+                min_fps: 10.0,
+                max_fps: DEFAULT_FPS,
+                min_height: 360,
+                max_height: DEFAULT_HEIGHT,
+                degradation: Degradation::MaintainFrameRate,
+                ..bounds
+            },
+        }
+    }
+
+    /// Filter frames are scaled with: sharp for text, cheap for motion.
+    pub fn scale_filter(self) -> FilterType {
+        match self {
+            ContentHint::Detail => FilterType::Lanczos3,
+            ContentHint::Motion => FilterType::Triangle,
+        }
+    }
+}
+
+/// Estimates the available send bandwidth from RTCP feedback.
+///
+/// Packet loss from receiver reports and transport-cc feedback drives a loss-based estimate,
+/// which REMB caps from above.
+#[derive(Debug)]
+pub struct BandwidthEstimator {
+    bounds: RateBounds,
+    loss_based: f64,
+    remb: Option<f64>,
+    last_increase: Option<Instant>,
+}
+
+impl BandwidthEstimator {
+    pub fn new(bounds: RateBounds) -> Self {
+        BandwidthEstimator {
+            loss_based: bounds.clamp_bitrate(bounds.start_bitrate as f64),
+            bounds,
+            remb: None,
+            last_increase: None,
+        }
+    }
+
+    /// Current estimate in bits per second.
+    pub fn estimate(&self) -> u32 {
+        let estimate = match self.remb {
+            Some(remb) => self.loss_based.min(remb),
+            None => self.loss_based,
+        };
+        self.bounds.clamp_bitrate(estimate) as u32
+    }
+
+    pub fn on_remb(&mut self, bitrate: f64) {
+        self.remb = Some(bitrate);
+        // Never probe far above what the receiver says it can take.
+        self.loss_based = self.loss_based.min(bitrate * INCREASE_FACTOR);
+    }
+
+    /// Feed the fraction of packets lost, between 0 and 1, since the previous report.
+    pub fn on_loss(&mut self, loss: f64, now: Instant) {
+        if loss > LOSS_DECREASE_THRESHOLD {
+            self.loss_based *= 1.0 - 0.5 * loss.min(1.0);
+        } else if loss < LOSS_INCREASE_THRESHOLD {
+            let due = self
+                .last_increase
+                .is_none_or(|t| now.duration_since(t) >= INCREASE_INTERVAL);
+            if !due {
+                return;
+            }
+            self.loss_based *= INCREASE_FACTOR;
+            self.last_increase = Some(now);
+        }
+        self.loss_based = self.bounds.clamp_bitrate(self.loss_based);
+    }
+
+    /// Feed every RTCP packet read from the sender; returns whether one carried feedback.
+    pub fn on_rtcp(&mut self, packets: &[Box<dyn Packet + Send + Sync>], now: Instant) -> bool {
+        let mut handled = false;
+        for packet in packets {
+            let any = packet.as_any();
+            if let Some(remb) = any.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                self.on_remb(remb.bitrate as f64);
+            } else if let Some(rr) = any.downcast_ref::<ReceiverReport>() {
+                let Some(worst) = rr.reports.iter().map(|r| r.fraction_lost).max() else {
+                    continue;
+                };
+                self.on_loss(worst as f64 / 256.0, now);
+            } else if let Some(cc) = any.downcast_ref::<TransportLayerCc>() {
+                let Some(loss) = transport_cc_loss(cc) else {
+                    continue;
+                };
+                self.on_loss(loss, now);
+            } else {
+                continue;
+            }
+            handled = true;
+        }
+        handled
+    }
+}
+
+/// Fraction of the packets covered by `cc` that never arrived.
+fn transport_cc_loss(cc: &TransportLayerCc) -> Option<f64> {
+    let mut lost = 0usize;
+    let mut total = 0usize;
+    for chunk in &cc.packet_chunks {
+        match chunk {
+            PacketStatusChunk::RunLengthChunk(run) => {
+                total += run.run_length as usize;
+                if run.packet_status_symbol == SymbolTypeTcc::PacketNotReceived {
+                    lost += run.run_length as usize;
+                }
+            }
+            PacketStatusChunk::StatusVectorChunk(vector) => {
+                total += vector.symbol_list.len();
+                lost += vector
+                    .symbol_list
+                    .iter()
+                    .filter(|s| **s == SymbolTypeTcc::PacketNotReceived)
+                    .count();
+            }
+        }
+    }
+    // Chunks are padded past the last packet, so only count the packets reported on.
+    let total = total.min(cc.packet_status_count as usize);
+    (total > 0).then(|| lost.min(total) as f64 / total as f64)
+}
+
+struct RateState {
+    estimator: BandwidthEstimator,
+    settings: VideoSettings,
+    content_hint: Option<ContentHint>,
+    bounds: RateBounds,
+}
+
+/// Turns RTCP feedback into [`VideoSettings`] and applies the frame rate to a running capture.
+///
+/// Only the frame rate and resolution take effect for now. The bitrate goes to
+/// [`crate::encoder::VideoEncoder::set_bitrate`], and [`crate::encoder::PassthroughEncoder`]
+/// sends raw frames whatever it is; that part is inert until a real encoder exists.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct RateController {
+    state: Arc<Mutex<RateState>>,
+    /// Bounds as configured, before a content hint narrows them.
+    base_bounds: RateBounds,
+    frame_rate: FrameRate,
+}
+
+impl RateController {
+    pub fn new(bounds: RateBounds, frame_rate: FrameRate) -> Self {
+        let estimator = BandwidthEstimator::new(bounds);
+        let mut settings = VideoSettings::for_bitrate(estimator.estimate(), &bounds);
+        // Until feedback arrives the capture keeps the frame rate it was configured with.
+        settings.frame_rate = frame_rate.get();
+        RateController {
+            state: Arc::new(Mutex::new(RateState {
+                estimator,
+                settings,
+                content_hint: None,
+                bounds,
+            })),
+            base_bounds: bounds,
+            frame_rate,
+        }
+    }
+
+    /// Bounds currently applied, narrowed by the content hint if one is set.
+    pub fn bounds(&self) -> RateBounds {
+        self.state.lock().unwrap().bounds
+    }
+
+    pub fn content_hint(&self) -> Option<ContentHint> {
+        self.state.lock().unwrap().content_hint
+    }
+
+    /// Tune frame rate and resolution for `hint` right away, or go back to the configured
+    /// bounds with `None`. The encoder is told too, which changes nothing for now.
+    pub fn set_content_hint(&self, hint: Option<ContentHint>) {
+        let mut state = self.state.lock().unwrap();
+        state.content_hint = hint;
+        state.bounds = match hint {
+            Some(hint) => hint.bounds(self.base_bounds),
+            None => self.base_bounds,
+        };
+        let settings = VideoSettings::for_bitrate(state.estimator.estimate(), &state.bounds);
+        println!(
+            "Content hint {:?}: {:.1} fps at {}p",
+            hint, settings.frame_rate, settings.height
+        );
+        state.settings = settings;
+        self.frame_rate.set(settings.frame_rate);
+    }
+
+    pub fn settings(&self) -> VideoSettings {
+        self.state.lock().unwrap().settings
+    }
+
+    /// Current bandwidth estimate in bits per second.
+    pub fn estimate(&self) -> u32 {
+        self.state.lock().unwrap().estimator.estimate()
+    }
+
+    /// Update the estimate from RTCP packets read from the sender and adapt the settings.
+    pub fn on_rtcp(&self, packets: &[Box<dyn Packet + Send + Sync>]) {
+        let mut state = self.state.lock().unwrap();
+        if !state.estimator.on_rtcp(packets, Instant::now()) {
+            return;
+        }
+        let settings = VideoSettings::for_bitrate(state.estimator.estimate(), &state.bounds);
+        if settings != state.settings {
+            println!(
+                "Bandwidth estimate {} kbps: {:.1} fps at {}p",
+                settings.bitrate / 1000,
+                settings.frame_rate,
+                settings.height
+            );
+            state.settings = settings;
+        }
+        self.frame_rate.set(settings.frame_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtcp::reception_report::ReceptionReport;
+    use webrtc::rtcp::transport_feedbacks::transport_layer_cc::RunLengthChunk;
+
+    #[test]
+    fn settings_drop_frame_rate_before_resolution() {
+        let bounds = RateBounds::default();
+        let full = VideoSettings::for_bitrate(bounds.max_bitrate, &bounds);
+        assert_eq!(
+            (full.frame_rate, full.height),
+            (DEFAULT_FPS, DEFAULT_HEIGHT)
+        );
+
+        let mid = VideoSettings::for_bitrate(800_000, &bounds);
+        assert!(mid.frame_rate < DEFAULT_FPS);
+        assert_eq!(mid.height, DEFAULT_HEIGHT);
+
+        let floor = VideoSettings::for_bitrate(1, &bounds);
+        assert_eq!(floor.bitrate, bounds.min_bitrate);
+        assert_eq!((floor.frame_rate, floor.height), (5.0, 360));
+    }
+
+    #[test]
+    fn loss_lowers_estimate_and_remb_caps_it() {
+        let mut estimator = BandwidthEstimator::new(RateBounds::default());
+        let now = Instant::now();
+        estimator.on_loss(0.2, now);
+        assert_eq!(estimator.estimate(), 900_000);
+
+        estimator.on_loss(0.0, now);
+        estimator.on_loss(0.0, now + Duration::from_millis(100));
+        assert_eq!(estimator.estimate(), 972_000);
+
+        estimator.on_remb(300_000.0);
+        assert_eq!(estimator.estimate(), 300_000);
+        estimator.on_loss(0.9, now);
+        assert_eq!(estimator.estimate(), 178_200);
+    }
+
+    #[test]
+    fn rtcp_feedback_adapts_frame_rate() {
+        let frame_rate = FrameRate::new(30.0);
+        let controller = RateController::new(RateBounds::default(), frame_rate.clone());
+        let report: Box<dyn Packet + Send + Sync> = Box::new(ReceiverReport {
+            reports: vec![ReceptionReport {
+                fraction_lost: 128,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let feedback: Box<dyn Packet + Send + Sync> = Box::new(TransportLayerCc {
+            packet_status_count: 10,
+            packet_chunks: vec![PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+                packet_status_symbol: SymbolTypeTcc::PacketNotReceived,
+                run_length: 10,
+                ..Default::default()
+            })],
+            ..Default::default()
+        });
+        controller.on_rtcp(&[report, feedback]);
+
+        // The estimate is the bitrate a real encoder would be given; only the frame rate
+        // below changes what is sent today.
+        assert_eq!(controller.estimate(), 375_000);
+        assert_eq!(frame_rate.get(), controller.settings().frame_rate);
+        assert!(frame_rate.get() < 30.0);
+    }
+
+    #[test]
+    fn content_hint_switches_what_gives_way() {
+        let frame_rate = FrameRate::new(30.0);
+        let controller = RateController::new(RateBounds::default(), frame_rate.clone());
+
+        // Only frame rate and resolution can be checked; the passthrough encoder ignores hints.
+        controller.set_content_hint(Some(ContentHint::Detail));
+        let detail = controller.settings();
+        assert!(detail.height > DEFAULT_HEIGHT);
+        assert!(detail.frame_rate <= 10.0);
+        assert_eq!(frame_rate.get(), detail.frame_rate);
+
+        controller.set_content_hint(Some(ContentHint::Motion));
+        let motion = controller.settings();
+        assert_eq!(motion.frame_rate, DEFAULT_FPS);
+        assert!(motion.height < DEFAULT_HEIGHT);
+
+        controller.set_content_hint(None);
+        assert_eq!(controller.bounds(), RateBounds::default());
+    }
+}
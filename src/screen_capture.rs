@@ -6,33 +6,109 @@ use std::thread;
 use std::time::Duration;
 
 use crate::broad_cast::get_client_boradcast_enable;
+use crate::damage::{DamageTracker, DirtyRect};
+use crate::frame_source::{FrameSource, RawFrame};
+use crate::rate_control::DEFAULT_HEIGHT;
 
 pub fn capture_screen() -> Result<Vec<u8>> {
-    // Only send data of primary display
-    let display = Display::primary()?;
-    let (width, height): (u32, u32) = (display.width() as u32, display.height() as u32);
-    let mut capturer = Capturer::new(display)?;
-    loop {
-        if get_client_boradcast_enable() == false {
-            break Ok(vec![]);
-        }
+    match ScreenSource::primary()?.next_frame()? {
+        Some(frame) => Ok(image_compress(
+            frame.data,
+            frame.width,
+            frame.height,
+            DEFAULT_HEIGHT,
+            FilterType::Lanczos3,
+        )?
+        .0),
+        None => Ok(vec![]),
+    }
+}
 
-        match capturer.frame() {
-            Ok(buffer) => {
-                print!("Captured screen. ");
-                // print_image_size(&buffer.to_vec());
-                let buffer = image_compress(buffer.to_vec(), width, height)?;
-                // save_rgb_image_from_bytes(buffer, width, height);
-                // handle_admin_binary_events(&mut window, Bytes::from(buffer)).unwrap();
-                // return buffer.to_vec();
-                return Ok(buffer);
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                thread::sleep(Duration::from_millis(100));
+/// Scale `frame` to `target_height` with `filter` only when `tracker` finds damage
+/// compared to the previous frame. Frames no taller than `target_height` keep their size.
+///
+/// Returns the compressed frame with its dirty regions in compressed-frame coordinates,
+/// or `None` when nothing changed.
+pub fn compress_damaged_frame(
+    frame: RawFrame,
+    tracker: &mut DamageTracker,
+    target_height: u32,
+    filter: FilterType,
+) -> Result<Option<(RawFrame, Vec<DirtyRect>)>> {
+    let (width, height) = (frame.width, frame.height);
+    let damage = tracker.detect(&frame.data, width, height);
+    if damage.is_unchanged() {
+        return Ok(None);
+    }
+
+    let (buffer, new_width, new_height) =
+        image_compress(frame.data, width, height, target_height, filter)?;
+    let (sx, sy) = (
+        new_width as f64 / width as f64,
+        new_height as f64 / height as f64,
+    );
+    let regions = damage.regions().iter().map(|r| r.scale(sx, sy)).collect();
+    let compressed = RawFrame {
+        data: buffer,
+        width: new_width,
+        height: new_height,
+        captured_at: frame.captured_at,
+    };
+    Ok(Some((compressed, regions)))
+}
+
+/// [`FrameSource`] backed by a `scrap` capturer of the primary display.
+pub struct ScreenSource {
+    capturer: Capturer,
+    width: u32,
+    height: u32,
+    last_frame: Option<Vec<u8>>,
+}
+
+impl ScreenSource {
+    pub fn primary() -> Result<Self> {
+        // Only send data of primary display
+        let display = Display::primary()?;
+        let (width, height): (u32, u32) = (display.width() as u32, display.height() as u32);
+        let capturer = Capturer::new(display)?;
+        Ok(ScreenSource {
+            capturer,
+            width,
+            height,
+            last_frame: None,
+        })
+    }
+}
+
+impl FrameSource for ScreenSource {
+    fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        loop {
+            if !get_client_boradcast_enable() {
+                break Ok(None);
             }
-            Err(e) => {
-                eprintln!("Failed to capture screen: {:?}", e);
-                thread::sleep(Duration::from_millis(100));
+
+            match self.capturer.frame() {
+                Ok(buffer) => {
+                    print!("Captured screen. ");
+                    // print_image_size(&buffer.to_vec());
+                    // save_rgb_image_from_bytes(buffer, width, height);
+                    // handle_admin_binary_events(&mut window, Bytes::from(buffer)).unwrap();
+                    let buffer = buffer.to_vec();
+                    self.last_frame = Some(buffer.clone());
+                    return Ok(Some(RawFrame::new(buffer, self.width, self.height)));
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    // No new frame means the screen is unchanged, so hand back the last one
+                    // and let the damage tracker decide whether a keepalive is due.
+                    if let Some(buffer) = self.last_frame.as_ref() {
+                        return Ok(Some(RawFrame::new(buffer.clone(), self.width, self.height)));
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    eprintln!("Failed to capture screen: {:?}", e);
+                    thread::sleep(Duration::from_millis(100));
+                }
             }
         }
     }
@@ -65,30 +141,44 @@ pub fn save_rgb_image_from_bytes(bytes: Vec<u8>, width: u32, height: u32) -> Res
     Ok(())
 }
 
-fn image_compress(bytes: Vec<u8>, width: u32, height: u32) -> Result<Vec<u8>> {
+fn image_compress(
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+    target_height: u32,
+    filter: FilterType,
+) -> Result<(Vec<u8>, u32, u32)> {
     // print_image_size(bytes.clone());
+    if height <= target_height {
+        return Ok((bytes, width, height));
+    }
 
     if let Some(rgb_img) =
         // ImageBuffer<image::Rgba<u8>, Vec<u8>> =
         ImageBuffer::from_raw(width, height, bytes)
     {
         let mut dynamic_img = DynamicImage::ImageRgba8(rgb_img);
-        dynamic_img = scale_to_fixed_height(&mut dynamic_img, 720);
+        dynamic_img = scale_to_fixed_height(&mut dynamic_img, target_height, filter);
 
         let r = dynamic_img.to_rgba8();
         print_image_size(r.clone().into_raw());
-        return Ok(r.into_raw());
+        let (new_width, new_height) = r.dimensions();
+        return Ok((r.into_raw(), new_width, new_height));
     }
 
     bail!("Error converting image to DynamicImage")
 }
 
-fn scale_to_fixed_height(img: &mut DynamicImage, target_height: u32) -> DynamicImage {
-    let (orig_width, orig_height) = (img.width() as u32, img.height() as u32);
+fn scale_to_fixed_height(
+    img: &mut DynamicImage,
+    target_height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    let (orig_width, orig_height) = (img.width(), img.height());
     let aspect_ratio = orig_width as f32 / orig_height as f32;
     let new_width = (aspect_ratio * target_height as f32) as u32;
     println!("width: {}, height: {} || ", new_width, target_height);
-    img.resize(new_width, target_height, FilterType::Lanczos3)
+    img.resize(new_width, target_height, filter)
 }
 
 fn print_image_size(image_bytes: Vec<u8>) {
@@ -1,20 +1,24 @@
 use anyhow::{bail, Result};
-use indexmap::IndexMap;
-use scrap::Display;
 use std::{
-    io::Bytes,
-    sync::{Arc, Mutex},
-    thread,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::{runtime::Runtime, sync::oneshot};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
 use tokio_tungstenite::tungstenite::Message;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_VP8};
+use webrtc::peer_connection::certificate::RTCCertificate;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::rtp::codecs::vp8::Vp8Packet;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType};
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
-use webrtc::track::track_local::TrackLocal;
 use webrtc::{
-    api::interceptor_registry::register_default_interceptors, interceptor::registry::Registry,
+    api::interceptor_registry::{configure_nack, configure_rtcp_reports, configure_twcc},
+    interceptor::registry::Registry,
 };
-use webrtc::{api::media_engine::MediaEngine, media::Sample};
 use webrtc::{api::APIBuilder, ice_transport::ice_server::RTCIceServer};
 use webrtc::{
     ice_transport::ice_candidate::RTCIceCandidate, peer_connection::configuration::RTCConfiguration,
@@ -23,27 +27,74 @@ use webrtc::{
     ice_transport::ice_candidate::RTCIceCandidateInit,
     peer_connection::sdp::session_description::RTCSessionDescription,
 };
-use webrtc::{
-    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
-    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
-};
 
 use crate::{
-    broad_cast::{
-        add_bytes_in_client_buffer, get_client_boradcast_enable, get_client_buffer_sender,
-        init_client_buffer, set_client_boradcast_enable,
-    },
-    client::FPS_LIMIT,
+    audio::{opus_capability, AudioHandle, AudioSource, OpusEncoder, SystemAudioSource},
+    auth::{self, Authenticator, Role},
+    capture::{CaptureConfig, CaptureHandle},
+    certificate::{self, Fingerprint},
+    clipboard::{ClipboardBackend, ClipboardConfig, ClipboardSync, CLIPBOARD_CHANNEL},
+    consent::{self, ConsentGate, ConsentOutcome, ViewerRequest},
+    cursor::CURSOR_CHANNEL,
+    file_transfer::{FileTransfer, TransferEvent, FILE_TRANSFER_CHANNEL},
+    frame_source::FrameSource,
+    input::{InputInjector, RemoteControl, CONTROL_CHANNEL},
+    jitter_buffer::{AssembledFrame, JitterBufferConfig, VideoJitterBuffer},
+    keyframe_request::{KeyframeReason, KeyframeRequestPolicy, KeyframeRequester, LossDetector},
+    messaging::{ChannelOptions, MessageChannel},
+    metrics::metrics,
     model::{SdpImpl, SdpOfferAnswer},
-    screen_capture::capture_screen,
-    RTC_CONFIG, RTC_TRACK,
+    playback::{self, AudioSink},
+    screen_capture::ScreenSource,
+    simulcast::{add_simulcast_tracks, register_simulcast_extensions, SimulcastConfig},
+    stats::FrameCounter,
+    RTC_AUDIO_TRACK, RTC_AUTHENTICATOR, RTC_CLIPBOARD_CHANNEL, RTC_CONFIG, RTC_CONSENT,
+    RTC_CONTROL_CHANNEL, RTC_CURSOR_CHANNEL, RTC_FILE_CHANNEL, RTC_LAYER_TRACKS,
+    RTC_REMOTE_FINGERPRINT, RTC_SENDER, RTC_TRACK,
 };
 
+/// Name the peer connection in [`RTC_CONFIG`] is reported under in the metrics.
+pub const PRIMARY_SESSION: &str = "primary";
+
+/// Reassembled frames [`get_client_frame`] holds for a consumer that falls behind.
+pub const RECEIVED_FRAME_QUEUE: usize = 8;
+
+/// How the screen is sent by the peer connection [`init_sdp_with`] creates.
+#[derive(Debug, Clone, Default)]
+pub struct SdpOptions {
+    /// Send the screen as these simulcast layers instead of a single track.
+    pub simulcast: Option<SimulcastConfig>,
+    /// Also send an Opus track, fed by [`start_audio_capture`].
+    pub audio: bool,
+    /// DTLS certificate to present, such as one from
+    /// [`certificate::load_or_generate_certificate`]; a new one each session otherwise.
+    pub certificate: Option<RTCCertificate>,
+}
+
 pub async fn init_sdp() -> Result<()> {
+    init_sdp_with(SdpOptions::default()).await
+}
+
+/// Like [`init_sdp`], but send the screen as the simulcast layers of `simulcast`.
+pub async fn init_simulcast_sdp(simulcast: SimulcastConfig) -> Result<()> {
+    init_sdp_with(SdpOptions {
+        simulcast: Some(simulcast),
+        ..Default::default()
+    })
+    .await
+}
+
+pub async fn init_sdp_with(options: SdpOptions) -> Result<()> {
     let mut media_engine = MediaEngine::default();
     media_engine.register_default_codecs()?;
+    // Needed to send simulcast and to tell incoming simulcast layers apart.
+    register_simulcast_extensions(&mut media_engine)?;
     let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut media_engine)?;
+    registry = configure_nack(registry, &mut media_engine);
+    registry = configure_rtcp_reports(registry);
+    // The defaults only send transport-cc feedback; sending the header extension too is
+    // what gets feedback on our own streams to the rate controller.
+    registry = configure_twcc(registry, &mut media_engine)?;
     let config = RTCConfiguration {
         ice_servers: vec![
             RTCIceServer {
@@ -61,18 +112,14 @@ pub async fn init_sdp() -> Result<()> {
                 credential: "password".to_string(),
             },
         ],
+        certificates: options.certificate.into_iter().collect(),
+        ..Default::default()
+    };
+    let codec = RTCRtpCodecCapability {
+        mime_type: MIME_TYPE_VP8.to_string(),
+        clock_rate: 90000,
         ..Default::default()
     };
-    let screen_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: "video/vp8".to_string(),
-            clock_rate: 90000,
-            ..Default::default()
-        },
-        "video".to_string(),
-        "screen_share".to_string(),
-    ));
-    RTC_TRACK.get_or_init(|| screen_track.clone());
 
     let rtpc = APIBuilder::new()
         .with_media_engine(media_engine)
@@ -83,11 +130,105 @@ pub async fn init_sdp() -> Result<()> {
     // rtpc.add_track(Arc::clone(&screen_track) as Arc<dyn TrackLocal + Send + Sync>)
     //     .await?;
 
-    rtpc.add_track(screen_track.clone()).await?;
-    RTC_CONFIG.get_or_init(|| Arc::new(rtpc));
+    let sender = match options.simulcast {
+        None => {
+            let screen_track = Arc::new(TrackLocalStaticSample::new(
+                codec,
+                "video".to_string(),
+                "screen_share".to_string(),
+            ));
+            RTC_TRACK.get_or_init(|| screen_track.clone());
+            rtpc.add_track(screen_track).await?
+        }
+        Some(simulcast) => {
+            let (sender, layers) = add_simulcast_tracks(&rtpc, codec, &simulcast).await?;
+            RTC_TRACK.get_or_init(|| layers[0].track.clone());
+            RTC_LAYER_TRACKS.get_or_init(|| layers);
+            sender
+        }
+    };
+    RTC_SENDER.get_or_init(|| sender);
+    if options.audio {
+        // Sharing the screen's stream id lets the receiver lip-sync the two tracks.
+        let audio_track = Arc::new(TrackLocalStaticSample::new(
+            opus_capability(),
+            "audio".to_string(),
+            "screen_share".to_string(),
+        ));
+        RTC_AUDIO_TRACK.get_or_init(|| audio_track.clone());
+        rtpc.add_track(audio_track).await?;
+    }
+    let cursor_channel = rtpc.create_data_channel(CURSOR_CHANNEL, None).await?;
+    RTC_CURSOR_CHANNEL.get_or_init(|| cursor_channel);
+    let control_channel = rtpc.create_data_channel(CONTROL_CHANNEL, None).await?;
+    RTC_CONTROL_CHANNEL.get_or_init(|| control_channel);
+    let clipboard_channel = rtpc.create_data_channel(CLIPBOARD_CHANNEL, None).await?;
+    RTC_CLIPBOARD_CHANNEL.get_or_init(|| clipboard_channel);
+    // Data channels are reliable and ordered unless configured otherwise.
+    let file_channel = rtpc
+        .create_data_channel(FILE_TRANSFER_CHANNEL, None)
+        .await?;
+    RTC_FILE_CHANNEL.get_or_init(|| file_channel);
+    // Disconnected is left alone: ICE often recovers from it.
+    rtpc.on_peer_connection_state_change(Box::new(|state| {
+        if matches!(
+            state,
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+        ) {
+            metrics().remove_session(PRIMARY_SESSION);
+            if RTC_CONSENT.get().is_some_and(|gate| gate.end().is_some()) {
+                auth::set_session(None);
+            }
+        }
+        Box::pin(async {})
+    }));
+    let rtpc = Arc::new(rtpc);
+    metrics().add_session(PRIMARY_SESSION, rtpc.clone());
+    RTC_CONFIG.get_or_init(|| rtpc);
+    Ok(())
+}
+
+/// Let the viewer drive the display through `injector`, such as
+/// [`crate::input::default_input_injector`], with messages on the control channel.
+pub fn enable_remote_control(injector: Box<dyn InputInjector>) -> Result<()> {
+    let Some(channel) = RTC_CONTROL_CHANNEL.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    RemoteControl::new(injector).attach(channel);
     Ok(())
 }
 
+/// Synchronize the clipboard through `backend`, such as
+/// [`crate::clipboard::CommandClipboard`], in the directions the user opted into.
+pub fn enable_clipboard_sync(
+    config: ClipboardConfig,
+    backend: Box<dyn ClipboardBackend>,
+) -> Result<()> {
+    let Some(channel) = RTC_CLIPBOARD_CHANNEL.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    ClipboardSync::new(config, backend).attach(channel.clone(), Duration::from_millis(500));
+    Ok(())
+}
+
+/// Send and receive files on the session, saving received ones into `download_dir`.
+pub async fn enable_file_transfer(
+    download_dir: PathBuf,
+) -> Result<(FileTransfer, mpsc::UnboundedReceiver<TransferEvent>)> {
+    let Some(channel) = RTC_FILE_CHANNEL.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    Ok(FileTransfer::attach(channel.clone(), download_dir).await)
+}
+
+/// Open an application messaging channel labelled `label` on the session.
+pub async fn open_message_channel(label: &str, options: ChannelOptions) -> Result<MessageChannel> {
+    let Some(rtpc) = RTC_CONFIG.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    MessageChannel::open(rtpc, label, options).await
+}
+
 pub async fn my_ice_candidate() -> Result<String> {
     let (tx, rx) = oneshot::channel::<String>();
     let tx_arc = Arc::new(Mutex::new(Some(tx)));
@@ -123,6 +264,9 @@ pub async fn create_sdp_offer() -> Result<SdpOfferAnswer> {
 
 pub async fn set_remote_answer_sdp(answer: &SdpOfferAnswer) -> Result<()> {
     let answer: RTCSessionDescription = serde_json::from_str(&answer.answer.clone().unwrap())?;
+    if let Some(pinned) = RTC_REMOTE_FINGERPRINT.get() {
+        certificate::verify_pinned_fingerprint(&answer.sdp, pinned)?;
+    }
 
     let peer_conn = RTC_CONFIG.get().unwrap();
     peer_conn.set_remote_description(answer).await?;
@@ -130,13 +274,112 @@ pub async fn set_remote_answer_sdp(answer: &SdpOfferAnswer) -> Result<()> {
     Ok(())
 }
 
-pub async fn create_sdp_answer(sdp_offer: String, client_id: &str) -> Result<Message> {
+/// Require every offer to carry a token `authenticator` accepts, see [`answer_signed_offer`].
+/// The role it grants then limits what the session may do.
+pub fn enable_authentication(authenticator: Authenticator) -> Result<()> {
+    if RTC_AUTHENTICATOR.set(authenticator).is_err() {
+        bail!("Authentication is already enabled");
+    }
+    Ok(())
+}
+
+/// Ask the local user through `gate` before answering a viewer's offer, and send the screen
+/// only while the viewer let in keeps that consent. The session ends when the connection
+/// fails or closes.
+pub fn enable_consent(gate: ConsentGate) -> Result<()> {
+    if RTC_CONFIG.get().is_none() {
+        bail!("Peer connection is not initialized");
+    }
+    if RTC_CONSENT.set(gate).is_err() {
+        bail!("Consent is already enabled");
+    }
+    Ok(())
+}
+
+/// Take the active viewer's consent back: nothing more is sent and the viewer loses control.
+/// The peer connection stays open, so a later offer can start a new session once the user
+/// consents again. Returns who was watching.
+pub async fn revoke_session() -> Result<Option<ViewerRequest>> {
+    let Some(viewer) = RTC_CONSENT.get().and_then(|gate| gate.revoke()) else {
+        return Ok(None);
+    };
+    auth::set_session(None);
+    metrics().remove_session(PRIMARY_SESSION);
+    Ok(Some(viewer))
+}
+
+/// The fingerprint of our DTLS certificate, for the viewer to verify out of band.
+pub fn local_fingerprint() -> Result<Fingerprint> {
+    let Some(rtpc) = RTC_CONFIG.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    let parameters = rtpc.dtls_transport().get_local_parameters()?;
+    let Some(fingerprint) = parameters.fingerprints.first() else {
+        bail!("Peer connection has no certificate");
+    };
+    Ok(fingerprint.into())
+}
+
+/// Accept only offers and answers whose DTLS fingerprint is `fingerprint`, as verified
+/// out of band.
+pub fn pin_remote_fingerprint(fingerprint: Fingerprint) -> Result<()> {
+    if RTC_REMOTE_FINGERPRINT.set(fingerprint).is_err() {
+        bail!("A remote fingerprint is already pinned");
+    }
+    Ok(())
+}
+
+/// Answer the offer in a signaling `message`. With authentication enabled its token is
+/// verified first, and the client only becomes the current session once it is answered.
+pub async fn answer_signed_offer(message: &SdpOfferAnswer) -> Result<Message> {
+    let Some(offer) = message.offer.clone() else {
+        bail!("Signaling message carries no offer");
+    };
+    let Some(authenticator) = RTC_AUTHENTICATOR.get() else {
+        let client_id = message.client_id.clone().unwrap_or_default();
+        return create_sdp_answer(offer, &client_id, None).await;
+    };
+    let session = match authenticator.authenticate(message) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Rejected offer from {:?}: {}", message.client_id, e);
+            return Err(e);
+        }
+    };
+    println!("Authenticated {} as {:?}", session.client_id, session.role);
+    let answer = create_sdp_answer(offer, &session.client_id, Some(session.role)).await?;
+    auth::set_session(Some(session));
+    Ok(answer)
+}
+
+/// Answer `sdp_offer` from `client_id`, authenticated with `role` if authentication is on.
+async fn create_sdp_answer(
+    sdp_offer: String,
+    client_id: &str,
+    role: Option<Role>,
+) -> Result<Message> {
     println!("Received SDP offer: {:?}", sdp_offer);
     let offer: RTCSessionDescription = serde_json::from_str(&sdp_offer)?;
+    // Checked before asking the user, who should not be asked about an impostor.
+    if let Some(pinned) = RTC_REMOTE_FINGERPRINT.get() {
+        certificate::verify_pinned_fingerprint(&offer.sdp, pinned)?;
+    }
+    if let Some(gate) = RTC_CONSENT.get() {
+        let request = ViewerRequest {
+            client_id: client_id.to_string(),
+            role,
+        };
+        let outcome = gate.request(request).await;
+        if outcome != ConsentOutcome::Accepted {
+            bail!("Viewer {} was not let in: {:?}", client_id, outcome);
+        }
+    }
     let rtpc = RTC_CONFIG.get().unwrap();
     rtpc.set_remote_description(offer).await?;
     let sdp_answer = rtpc.create_answer(None).await?;
     rtpc.set_local_description(sdp_answer.clone()).await?;
+    // A revoked session stopped being reported; this answer starts a new one.
+    metrics().add_session(PRIMARY_SESSION, rtpc.clone());
 
     let offer = SdpOfferAnswer::new(
         None,
@@ -157,137 +400,180 @@ pub async fn set_ice_candidate(ice: String) -> Result<()> {
     Ok(())
 }
 
-pub fn start_screen_capture_loop() -> Result<()> {
-    init_client_buffer();
-    match Display::primary() {
-        Ok(_) => {
-            set_client_boradcast_enable(true);
+pub fn start_screen_capture_loop() -> Result<CaptureHandle> {
+    start_capture_loop(ScreenSource::primary, CaptureConfig::default())
+}
 
-            thread::spawn(move || {
-                let frame_time = Duration::from_millis(1000 / FPS_LIMIT as u64);
-                loop {
-                    let start_time = Instant::now();
+/// Start capturing frames from the source built by `open_source`, see [`CaptureHandle::start`].
+pub fn start_capture_loop<F, S>(open_source: F, config: CaptureConfig) -> Result<CaptureHandle>
+where
+    F: FnOnce() -> Result<S> + Send + 'static,
+    S: FrameSource,
+{
+    consent::ensure_sharing_allowed()?;
+    CaptureHandle::start(open_source, config)
+}
 
-                    if get_client_boradcast_enable() == false {
+/// Send what the system plays on the audio track.
+pub fn start_system_audio_capture() -> Result<AudioHandle> {
+    start_audio_capture(SystemAudioSource::open)
+}
+
+/// Send frames from the source built by `open_source` on the audio track, see
+/// [`AudioHandle::start`]. The session must have been initialized with audio.
+pub fn start_audio_capture<F, S>(open_source: F) -> Result<AudioHandle>
+where
+    F: FnOnce() -> Result<S> + Send + 'static,
+    S: AudioSource,
+{
+    AudioHandle::start(open_source, Box::new(OpusEncoder::new()?))
+}
+
+/// Play incoming audio tracks picked up by [`get_client_frame`] into sinks built by `factory`,
+/// such as [`crate::playback::SystemAudioSink::open`].
+pub fn enable_audio_playback<F>(factory: F)
+where
+    F: Fn() -> Result<Box<dyn AudioSink>> + Send + Sync + 'static,
+{
+    playback::set_sink_factory(factory);
+}
+
+/// A VP8 frame of an incoming video track, reassembled by its jitter buffer.
+#[derive(Debug, Clone)]
+pub struct ReceivedFrame {
+    pub ssrc: u32,
+    /// Simulcast layer the track carries; empty without simulcast.
+    pub rid: String,
+    pub frame: AssembledFrame,
+}
+
+/// Read incoming tracks, asking the sender for keyframes only when decoding needs one:
+/// when a track starts, when NACK fails to recover lost packets, and when a decoder reports
+/// an error through the returned [`KeyframeRequester`].
+///
+/// Complete frames of VP8 tracks arrive on the returned receiver, for a decoder to consume.
+/// Once [`RECEIVED_FRAME_QUEUE`] frames are waiting, newer ones are dropped. Every frame
+/// reassembled is counted in `frames_received`, for
+/// [`crate::stats::StatsCollector::with_frames_received`].
+pub fn get_client_frame(
+    policy: KeyframeRequestPolicy,
+    frames_received: FrameCounter,
+) -> Result<(KeyframeRequester, mpsc::Receiver<ReceivedFrame>)> {
+    let Some(rtpc) = RTC_CONFIG.get() else {
+        bail!("Peer connection is not initialized");
+    };
+    let requester = KeyframeRequester::new(policy);
+    let handler_requester = requester.clone();
+    let (frame_tx, frame_rx) = mpsc::channel(RECEIVED_FRAME_QUEUE);
+    rtpc.on_track(Box::new(move |track, _, _| {
+        if track.kind() == RTPCodecType::Audio {
+            playback::play_remote_track(track);
+            return Box::pin(async {});
+        }
+        let media_ssrc = track.ssrc();
+        // Each simulcast layer arrives as its own track with its own SSRC and RID.
+        println!(
+            "Track has started, rid: {}, ssrc: {}",
+            track.rid(),
+            media_ssrc
+        );
+        let requester = handler_requester.clone();
+        requester.request(media_ssrc, KeyframeReason::TrackStart);
+        let vp8 = track
+            .codec()
+            .capability
+            .mime_type
+            .eq_ignore_ascii_case(MIME_TYPE_VP8);
+        let frames = frame_tx.clone();
+        let frames_received = frames_received.clone();
+
+        tokio::spawn(async move {
+            println!("enter track loop {}", track.rid());
+            let (packet_tx, mut packets) = mpsc::unbounded_channel();
+            let reader = track.clone();
+            tokio::spawn(async move {
+                while let Ok((rtp, _)) = reader.read_rtp().await {
+                    if packet_tx.send(rtp).is_err() {
                         break;
                     }
-                    match capture_screen() {
-                        Ok(screen_data) => {
-                            if screen_data.is_empty() {
-                                println!("Screen data is empty");
-                                continue;
-                            }
-                            add_bytes_in_client_buffer(screen_data);
-                            let elapsed = start_time.elapsed();
-                            if elapsed < frame_time {
-                                thread::sleep(frame_time - elapsed);
-                            }
-                        }
-                        Err(e) => eprintln!("Failed to capture screen: {:?}", e),
-                    }
                 }
             });
-        }
-        Err(e) => bail!("Failed to find primary Display with : {:?}", e),
-    }
-    println!("Screen capture loop will be started");
-    std::thread::spawn(move || {
-        Runtime::new().unwrap().block_on(async {
-            let receiver = get_client_buffer_sender();
-            let mut receiver = receiver.subscribe();
-
+            let nack_window = requester.policy().nack_window;
+            // Other codecs are only watched for loss.
+            let mut loss = LossDetector::new(nack_window);
+            let mut buffer = vp8.then(|| {
+                VideoJitterBuffer::new(
+                    Box::<Vp8Packet>::default(),
+                    JitterBufferConfig {
+                        nack_window,
+                        ..Default::default()
+                    },
+                )
+            });
+            let mut unconsumed = 0u64;
+            // A gap followed by a stall gets no packet to expire it, so a timer does too.
+            let mut ticks = tokio::time::interval(nack_window / 2);
             loop {
-                tokio::select! {
-                  Ok(buffer) = receiver.recv() => {
-                    let b= buffer;
-                    if b.is_empty() {
-                        println!("Received empty buffer from broadcast channel");
-                        continue;
-                    }
-                    match RTC_TRACK.get(){
-                        Some(t) => {
-                            match t.write_sample(&Sample {
-                                data: b.into(),
-                                duration: Duration::from_millis(33),
-                                ..Default::default()
-                            })
-                            .await
-                            {
-                                Ok(_) => println!("Sent frame to WebRTC track"  ),
-                                Err(e) => eprintln!("Error sending fram {}",   e),
-                            }
-                        }
-                        None=>{
-                            println!("RTC_TRACK is None, cannot send frame to WebRTC track");
-                            continue;
-                        }
+                let (lost, now) = tokio::select! {
+                    rtp = packets.recv() => {
+                        let Some(rtp) = rtp else {
+                            break;
+                        };
+                        let now = Instant::now();
+                        let lost = match &mut buffer {
+                            Some(buffer) => buffer.push(&rtp, now),
+                            None => loss.on_packet(rtp.header.sequence_number, now),
+                        };
+                        (lost, now)
                     }
-                }}
-            }
-        });
-    });
-    Ok(())
-}
-
-pub fn get_client_frame() -> Result<()> {
-    std::thread::spawn(move || {
-        loop {
-            let rtpc = RTC_CONFIG.get().unwrap();
-
-            rtpc.on_track(Box::new(move |track, _, _| {
-                println!("Track has started");
-
-                let rid = track.rid().to_owned();
-                // let output_track = if let Some(output_track) = output_tracks.get(&rid) {
-                //     Arc::clone(output_track)
-                // } else {
-                //     println!("output_track not found for rid = {rid}");
-                //     return Box::pin(async {});
-                // };
-
-                // Start reading from all the streams and sending them to the related output track
-                let media_ssrc = track.ssrc();
-                let pc2 = rtpc.clone();
-                tokio::spawn(async move {
-                    let mut result = Result::<usize>::Ok(0);
-                    while result.is_ok() {
-                        println!("Sending pli for stream with rid: {rid}, ssrc: {media_ssrc}");
-
-                        let timeout = tokio::time::sleep(Duration::from_secs(3));
-                        tokio::pin!(timeout);
-
-                        tokio::select! {
-                            _ = timeout.as_mut() =>{
-
-                                    result = pc2.write_rtcp(&[Box::new(PictureLossIndication{
-                                        sender_ssrc: 0,
-                                        media_ssrc,
-                                    })]).await.map_err(Into::into);
-
-                            }
+                    _ = ticks.tick() => {
+                        let now = Instant::now();
+                        let lost = match &mut buffer {
+                            Some(buffer) => buffer.expire(now),
+                            None => loss.expired(now),
                         };
+                        (lost, now)
+                    }
+                };
+                if lost {
+                    requester.request(media_ssrc, KeyframeReason::UnrecoverableLoss);
+                }
+                if let Some(packet) = requester.take(media_ssrc, now) {
+                    if let Err(e) = rtpc.write_rtcp(&[packet]).await {
+                        eprintln!("Error sending keyframe request {}", e);
                     }
-                });
-
-                tokio::spawn(async move {
-                    println!("enter track loop {}", track.rid());
-                    let mut map = IndexMap::new();
-                    while let Ok((rtp, _)) = track.read_rtp().await {
-                        println!("h : {:?}", rtp.header);
-                        println!("p : {:?}", rtp.payload);
-                        if map.get(&rtp.header.timestamp).is_none() {
-                            map.clear();
-                            map.insert(rtp.header.timestamp, rtp.clone());
-                            println!("---------------------------------------------------");
-                            // println!("read rtp packet from track {:?}", rtp);
-                        }
+                }
+                let Some(buffer) = &mut buffer else {
+                    continue;
+                };
+                while let Some(frame) = buffer.pop_frame() {
+                    frames_received.fetch_add(1, Ordering::Relaxed);
+                    let frame = ReceivedFrame {
+                        ssrc: media_ssrc,
+                        rid: track.rid().to_string(),
+                        frame,
+                    };
+                    // Nobody may be consuming frames; reassembly still drives keyframe requests.
+                    if let Err(TrySendError::Full(_)) = frames.try_send(frame) {
+                        unconsumed += 1;
                     }
-                    println!("exit track loop {}", track.rid());
-                });
+                }
+                metrics().set_receive_stats(media_ssrc, Some(buffer.stats()));
+            }
+            if let Some(buffer) = &buffer {
+                println!(
+                    "track {} reassembly {:?}, {} frames dropped unconsumed",
+                    track.rid(),
+                    buffer.stats(),
+                    unconsumed
+                );
+            }
+            metrics().set_receive_stats(media_ssrc, None);
+            requester.remove(media_ssrc);
+            println!("exit track loop {}", track.rid());
+        });
 
-                Box::pin(async {})
-            }));
-        }
-    });
-    Ok(())
+        Box::pin(async {})
+    }));
+    Ok((requester, frame_rx))
 }
@@ -0,0 +1,233 @@
+use anyhow::{bail, Result};
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Rgba};
+use std::sync::Arc;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpHeaderExtensionCapability, RTPCodecType,
+};
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::sdp::extmap::{SDES_MID_URI, SDES_RTP_STREAM_ID_URI};
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+
+use crate::frame_bus::{FrameFormat, VideoFrame};
+use crate::RTC_LAYER_TRACKS;
+use crate::RTC_TRACK;
+
+/// One encoding of the outgoing video.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulcastLayer {
+    /// RTP stream id the layer is negotiated under; empty when simulcast is off.
+    pub rid: String,
+    /// The layer is the captured frame divided by this in both dimensions.
+    pub scale_down: u32,
+}
+
+impl SimulcastLayer {
+    pub fn new(rid: &str, scale_down: u32) -> Self {
+        SimulcastLayer {
+            rid: rid.to_string(),
+            scale_down,
+        }
+    }
+}
+
+/// Layers sent from one capture, highest resolution first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulcastConfig {
+    pub layers: Vec<SimulcastLayer>,
+}
+
+impl Default for SimulcastConfig {
+    /// Full, half and quarter resolution.
+    fn default() -> Self {
+        SimulcastConfig {
+            layers: vec![
+                SimulcastLayer::new("f", 1),
+                SimulcastLayer::new("h", 2),
+                SimulcastLayer::new("q", 4),
+            ],
+        }
+    }
+}
+
+impl SimulcastConfig {
+    fn validate(&self) -> Result<()> {
+        if self.layers.is_empty() {
+            bail!("Simulcast needs at least one layer");
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.rid.is_empty() || layer.scale_down == 0 {
+                bail!("Invalid simulcast layer {:?}", layer);
+            }
+            if self.layers[..i].iter().any(|l| l.rid == layer.rid) {
+                bail!("Duplicate simulcast rid {}", layer.rid);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A layer and the track its samples are written to.
+#[derive(Clone)]
+pub struct LayerTrack {
+    pub layer: SimulcastLayer,
+    pub track: Arc<TrackLocalStaticSample>,
+}
+
+/// Tracks the capture writes to: every simulcast layer, or just [`RTC_TRACK`] at full size.
+pub fn video_tracks() -> Vec<LayerTrack> {
+    if let Some(layers) = RTC_LAYER_TRACKS.get() {
+        return layers.clone();
+    }
+    RTC_TRACK
+        .get()
+        .map(|track| LayerTrack {
+            layer: SimulcastLayer::new("", 1),
+            track: track.clone(),
+        })
+        .into_iter()
+        .collect()
+}
+
+/// Share of the total bitrate for each layer, proportional to its pixel count.
+pub fn bitrate_shares(layers: &[SimulcastLayer]) -> Vec<f64> {
+    let weights: Vec<f64> = layers
+        .iter()
+        .map(|l| 1.0 / (l.scale_down.max(1) as f64).powi(2))
+        .collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / total).collect()
+}
+
+/// Register the RTP header extensions simulcast streams are identified by.
+pub fn register_simulcast_extensions(media_engine: &mut MediaEngine) -> Result<()> {
+    for uri in [SDES_MID_URI, SDES_RTP_STREAM_ID_URI] {
+        media_engine.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: uri.to_owned(),
+            },
+            RTPCodecType::Video,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+/// Add one track per layer to `peer_connection` as encodings of a single sender, so the
+/// offer carries an `a=rid` line per layer and `a=simulcast:send`.
+pub async fn add_simulcast_tracks(
+    peer_connection: &RTCPeerConnection,
+    codec: RTCRtpCodecCapability,
+    config: &SimulcastConfig,
+) -> Result<(Arc<RTCRtpSender>, Vec<LayerTrack>)> {
+    config.validate()?;
+    let tracks: Vec<LayerTrack> = config
+        .layers
+        .iter()
+        .map(|layer| LayerTrack {
+            layer: layer.clone(),
+            track: Arc::new(TrackLocalStaticSample::new_with_rid(
+                codec.clone(),
+                "video".to_string(),
+                layer.rid.clone(),
+                "screen_share".to_string(),
+            )),
+        })
+        .collect();
+    let sender = peer_connection.add_track(tracks[0].track.clone()).await?;
+    for layer in &tracks[1..] {
+        sender.add_encoding(layer.track.clone()).await?;
+    }
+    Ok((sender, tracks))
+}
+
+/// `frame` shrunk by `scale_down` in both dimensions, for a lower simulcast layer.
+pub fn scale_frame(frame: &VideoFrame, scale_down: u32) -> Result<VideoFrame> {
+    if scale_down <= 1 {
+        return Ok(frame.clone());
+    }
+    if frame.format != FrameFormat::Rgba {
+        bail!("Cannot scale {:?} frames", frame.format);
+    }
+    let Some(image) =
+        ImageBuffer::<Rgba<u8>, _>::from_raw(frame.width, frame.height, frame.data.as_ref())
+    else {
+        bail!("Frame data does not match {}x{}", frame.width, frame.height);
+    };
+    let (width, height) = (
+        (frame.width / scale_down).max(1),
+        (frame.height / scale_down).max(1),
+    );
+    let scaled = imageops::resize(&image, width, height, FilterType::Triangle);
+    let (sx, sy) = (
+        width as f64 / frame.width as f64,
+        height as f64 / frame.height as f64,
+    );
+    let mut layer = VideoFrame::new(
+        scaled.into_raw(),
+        width,
+        height,
+        frame.format,
+        frame.captured_at,
+    );
+    layer.keyframe = frame.keyframe;
+    layer.dirty = frame.dirty.iter().map(|r| r.scale(sx, sy)).collect();
+    layer.sequence = frame.sequence;
+    Ok(layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use webrtc::api::APIBuilder;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+
+    #[test]
+    fn layers_are_scaled_and_share_bitrate_by_area() {
+        let frame = VideoFrame::new(
+            vec![200; 8 * 4 * 4],
+            8,
+            4,
+            FrameFormat::Rgba,
+            Instant::now(),
+        );
+        let half = scale_frame(&frame, 2).unwrap();
+        assert_eq!((half.width, half.height, half.data.len()), (4, 2, 32));
+        assert!(half.data.iter().all(|b| *b == 200));
+
+        let shares = bitrate_shares(&SimulcastConfig::default().layers);
+        assert_eq!(shares, vec![16.0 / 21.0, 4.0 / 21.0, 1.0 / 21.0]);
+    }
+
+    #[tokio::test]
+    async fn offer_negotiates_every_rid() {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().unwrap();
+        register_simulcast_extensions(&mut media_engine).unwrap();
+        let pc = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let codec = RTCRtpCodecCapability {
+            mime_type: "video/vp8".to_string(),
+            clock_rate: 90000,
+            ..Default::default()
+        };
+        let (_, tracks) = add_simulcast_tracks(&pc, codec, &SimulcastConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(tracks.len(), 3);
+
+        let offer = pc.create_offer(None).await.unwrap().sdp;
+        for rid in ["f", "h", "q"] {
+            assert!(offer.contains(&format!("a=rid:{rid} send")), "{offer}");
+        }
+        assert!(offer.contains("a=simulcast:send f;h;q"), "{offer}");
+        pc.close().await.unwrap();
+    }
+}
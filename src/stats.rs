@@ -0,0 +1,337 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use webrtc::{
+    ice::candidate::{CandidatePairState, CandidateType},
+    peer_connection::RTCPeerConnection,
+    stats::{StatsReport, StatsReportType},
+};
+
+/// Frames counted by whoever produces or consumes them; webrtc-rs does not decode, so it
+/// cannot report frame rates itself.
+pub type FrameCounter = Arc<AtomicU64>;
+
+/// How media reaches the peer over the selected candidate pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// Host, server reflexive or peer reflexive candidates on both ends.
+    Direct,
+    /// At least one end goes through a TURN server.
+    Relay,
+}
+
+/// Metrics of one peer connection, derived from two consecutive `get_stats` reports.
+///
+/// Rates are averaged since the previous snapshot and are zero in the first one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionStats {
+    pub taken_at: Instant,
+    /// Bits per second.
+    pub outbound_bitrate: f64,
+    pub inbound_bitrate: f64,
+    /// Frames per second, when a [`FrameCounter`] is attached.
+    pub outbound_fps: Option<f64>,
+    pub inbound_fps: Option<f64>,
+    /// RTP bytes in total; counters, unlike the bitrates, do not depend on the previous snapshot.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub round_trip_time: Option<Duration>,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Packets the peer reported lost in total.
+    pub packets_lost: i64,
+    /// Fraction of packets lost in the peer's latest receiver report.
+    pub fraction_lost: f64,
+    /// `None` until a candidate pair has been selected.
+    pub connection: Option<ConnectionType>,
+}
+
+/// Cumulative counters a snapshot is derived from.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Totals {
+    bytes_sent: u64,
+    bytes_received: u64,
+    frames_sent: Option<u64>,
+    frames_received: Option<u64>,
+}
+
+fn per_second(current: u64, previous: u64, elapsed: f64) -> f64 {
+    if elapsed > 0.0 {
+        current.saturating_sub(previous) as f64 / elapsed
+    } else {
+        0.0
+    }
+}
+
+/// Polls `RTCPeerConnection::get_stats` and turns the reports into [`SessionStats`].
+pub struct StatsCollector {
+    peer_connection: Arc<RTCPeerConnection>,
+    frames_sent: Option<FrameCounter>,
+    frames_received: Option<FrameCounter>,
+    previous: Option<(Instant, Totals)>,
+}
+
+impl StatsCollector {
+    pub fn new(peer_connection: Arc<RTCPeerConnection>) -> Self {
+        StatsCollector {
+            peer_connection,
+            frames_sent: None,
+            frames_received: None,
+            previous: None,
+        }
+    }
+
+    /// Derive the outbound frame rate from `counter`, such as
+    /// [`crate::capture::CaptureHandle::frames_sent`].
+    pub fn with_frames_sent(mut self, counter: FrameCounter) -> Self {
+        self.frames_sent = Some(counter);
+        self
+    }
+
+    /// Derive the inbound frame rate from `counter`, such as the one given to
+    /// [`crate::sdp::get_client_frame`].
+    pub fn with_frames_received(mut self, counter: FrameCounter) -> Self {
+        self.frames_received = Some(counter);
+        self
+    }
+
+    pub fn peer_connection(&self) -> &Arc<RTCPeerConnection> {
+        &self.peer_connection
+    }
+
+    pub async fn snapshot(&mut self) -> SessionStats {
+        let report = self.peer_connection.get_stats().await;
+        self.derive(&report, Instant::now())
+    }
+
+    /// Take a snapshot every `interval` until the receiver is dropped.
+    pub fn stream(mut self, interval: Duration) -> mpsc::Receiver<SessionStats> {
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let stats = self.snapshot().await;
+                if tx.send(stats).await.is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    fn derive(&mut self, report: &StatsReport, now: Instant) -> SessionStats {
+        let load =
+            |counter: &Option<FrameCounter>| counter.as_ref().map(|c| c.load(Ordering::Relaxed));
+        let mut totals = Totals {
+            frames_sent: load(&self.frames_sent),
+            frames_received: load(&self.frames_received),
+            ..Default::default()
+        };
+        let mut stats = SessionStats {
+            taken_at: now,
+            outbound_bitrate: 0.0,
+            inbound_bitrate: 0.0,
+            outbound_fps: None,
+            inbound_fps: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            round_trip_time: None,
+            packets_sent: 0,
+            packets_received: 0,
+            packets_lost: 0,
+            fraction_lost: 0.0,
+            connection: None,
+        };
+        let mut pair_rtt = None;
+
+        for entry in report.reports.values() {
+            match entry {
+                StatsReportType::OutboundRTP(out) => {
+                    totals.bytes_sent += out.bytes_sent;
+                    stats.packets_sent += out.packets_sent;
+                }
+                StatsReportType::InboundRTP(inbound) => {
+                    totals.bytes_received += inbound.bytes_received;
+                    stats.packets_received += inbound.packets_received;
+                }
+                StatsReportType::RemoteInboundRTP(remote) => {
+                    stats.packets_lost += remote.packets_lost;
+                    stats.fraction_lost = stats.fraction_lost.max(remote.fraction_lost);
+                    if let Some(rtt) = remote.round_trip_time {
+                        stats.round_trip_time = Some(Duration::from_secs_f64(rtt));
+                    }
+                }
+                StatsReportType::CandidatePair(pair)
+                    if pair.nominated && pair.state == CandidatePairState::Succeeded =>
+                {
+                    let is_relay = |id: &str| {
+                        report.reports.get(id).is_some_and(|c| match c {
+                            StatsReportType::LocalCandidate(c)
+                            | StatsReportType::RemoteCandidate(c) => {
+                                c.candidate_type == CandidateType::Relay
+                            }
+                            _ => false,
+                        })
+                    };
+                    let relayed =
+                        is_relay(&pair.local_candidate_id) || is_relay(&pair.remote_candidate_id);
+                    stats.connection = Some(if relayed {
+                        ConnectionType::Relay
+                    } else {
+                        ConnectionType::Direct
+                    });
+                    if pair.current_round_trip_time > 0.0 {
+                        pair_rtt = Some(Duration::from_secs_f64(pair.current_round_trip_time));
+                    }
+                }
+                _ => {}
+            }
+        }
+        // RTCP round trips measure the media path; STUN checks are the fallback.
+        stats.round_trip_time = stats.round_trip_time.or(pair_rtt);
+        stats.bytes_sent = totals.bytes_sent;
+        stats.bytes_received = totals.bytes_received;
+
+        if let Some((at, previous)) = self.previous {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            stats.outbound_bitrate =
+                per_second(totals.bytes_sent, previous.bytes_sent, elapsed) * 8.0;
+            stats.inbound_bitrate =
+                per_second(totals.bytes_received, previous.bytes_received, elapsed) * 8.0;
+            stats.outbound_fps = totals
+                .frames_sent
+                .zip(previous.frames_sent)
+                .map(|(c, p)| per_second(c, p, elapsed));
+            stats.inbound_fps = totals
+                .frames_received
+                .zip(previous.frames_received)
+                .map(|(c, p)| per_second(c, p, elapsed));
+        }
+        self.previous = Some((now, totals));
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::api::APIBuilder;
+    use webrtc::ice::agent::agent_stats::CandidatePairStats;
+    use webrtc::ice::network_type::NetworkType;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::stats::{
+        ICECandidatePairStats, ICECandidateStats, RTCStatsType, RemoteInboundRTPStats,
+    };
+
+    async fn collector() -> StatsCollector {
+        let pc = APIBuilder::new()
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        StatsCollector::new(Arc::new(pc))
+    }
+
+    fn candidate(id: &str, candidate_type: CandidateType) -> StatsReportType {
+        StatsReportType::LocalCandidate(ICECandidateStats {
+            timestamp: tokio::time::Instant::now(),
+            stats_type: RTCStatsType::LocalCandidate,
+            id: id.to_string(),
+            candidate_type,
+            deleted: false,
+            ip: "192.0.2.1".to_string(),
+            network_type: NetworkType::Udp4,
+            port: 50000,
+            priority: 0,
+            relay_protocol: String::new(),
+            url: String::new(),
+        })
+    }
+
+    /// A selected pair from local candidate `local` to an unreported remote one.
+    fn selected_pair(local: &str, round_trip_time: f64) -> StatsReportType {
+        StatsReportType::CandidatePair(ICECandidatePairStats::from(CandidatePairStats {
+            local_candidate_id: local.to_string(),
+            remote_candidate_id: "remote".to_string(),
+            state: CandidatePairState::Succeeded,
+            nominated: true,
+            current_round_trip_time: round_trip_time,
+            ..Default::default()
+        }))
+    }
+
+    fn report(entries: Vec<StatsReportType>) -> StatsReport {
+        let reports = entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| match &entry {
+                StatsReportType::LocalCandidate(c) => (c.id.clone(), entry),
+                _ => (i.to_string(), entry),
+            })
+            .collect();
+        StatsReport { reports }
+    }
+
+    #[tokio::test]
+    async fn loss_and_round_trip_come_from_receiver_reports() {
+        let remote = RemoteInboundRTPStats {
+            timestamp: tokio::time::Instant::now(),
+            stats_type: RTCStatsType::RemoteInboundRTP,
+            id: "remote-inbound".to_string(),
+            ssrc: 1,
+            kind: "video".to_string(),
+            packets_received: 95,
+            packets_lost: 5,
+            local_id: "outbound".to_string(),
+            round_trip_time: Some(0.05),
+            total_round_trip_time: 0.05,
+            fraction_lost: 0.25,
+            round_trip_time_measurements: 1,
+        };
+        let report = report(vec![
+            StatsReportType::RemoteInboundRTP(remote),
+            candidate("host", CandidateType::Host),
+            selected_pair("host", 0.2),
+        ]);
+
+        let stats = collector().await.derive(&report, Instant::now());
+        assert_eq!((stats.packets_lost, stats.fraction_lost), (5, 0.25));
+        // The RTCP round trip wins over the STUN one.
+        assert_eq!(stats.round_trip_time, Some(Duration::from_millis(50)));
+        assert_eq!(stats.connection, Some(ConnectionType::Direct));
+    }
+
+    #[tokio::test]
+    async fn relayed_pair_is_told_apart_from_a_direct_one() {
+        let report = report(vec![
+            candidate("relay", CandidateType::Relay),
+            selected_pair("relay", 0.12),
+        ]);
+
+        let stats = collector().await.derive(&report, Instant::now());
+        assert_eq!(stats.connection, Some(ConnectionType::Relay));
+        assert_eq!(stats.round_trip_time, Some(Duration::from_millis(120)));
+        assert_eq!(stats.packets_lost, 0);
+    }
+
+    #[tokio::test]
+    async fn rates_come_from_counter_deltas() {
+        let frames: FrameCounter = Arc::default();
+        let mut collector = collector().await.with_frames_sent(frames.clone());
+        let report = StatsReport {
+            reports: Default::default(),
+        };
+
+        let start = Instant::now();
+        let first = collector.derive(&report, start);
+        assert_eq!((first.outbound_fps, first.connection), (None, None));
+
+        frames.fetch_add(15, Ordering::Relaxed);
+        let second = collector.derive(&report, start + Duration::from_millis(500));
+        assert_eq!(second.outbound_fps, Some(30.0));
+        assert_eq!(second.inbound_fps, None);
+        assert_eq!(second.outbound_bitrate, 0.0);
+    }
+}
@@ -0,0 +1,132 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::DEFAULT_FPS;
+
+/// Capture frame rate shared between the capture loop and whoever tunes it at runtime.
+///
+/// Clones share the same value, so keep a clone around to adjust a running loop.
+#[derive(Debug, Clone)]
+pub struct FrameRate(Arc<AtomicU64>);
+
+impl FrameRate {
+    pub fn new(fps: f64) -> Self {
+        FrameRate(Arc::new(AtomicU64::new(sanitize(fps).to_bits())))
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, fps: f64) {
+        self.0.store(sanitize(fps).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Time budget of one frame at the current rate.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.get())
+    }
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        FrameRate::new(DEFAULT_FPS)
+    }
+}
+
+fn sanitize(fps: f64) -> f64 {
+    if fps.is_finite() && fps > 0.0 {
+        fps.min(240.0)
+    } else {
+        DEFAULT_FPS
+    }
+}
+
+/// Derives `Sample` durations from monotonic capture timestamps.
+///
+/// The packetizer stamps a sample with the running RTP clock and then advances it by the
+/// sample's duration, so a sample's duration sets the timestamp of the one after it. Writers
+/// therefore hold each sample back until the next capture and give it the gap up to that
+/// capture. Durations are computed from the total elapsed time in clock ticks, so rounding
+/// never accumulates into drift.
+#[derive(Debug)]
+pub struct SampleClock {
+    clock_rate: u32,
+    origin: Option<Instant>,
+    emitted_ticks: u64,
+}
+
+impl SampleClock {
+    pub fn new(clock_rate: u32) -> Self {
+        SampleClock {
+            clock_rate,
+            origin: None,
+            emitted_ticks: 0,
+        }
+    }
+
+    /// Duration to write with the sample captured at `captured_at`, given that the next one
+    /// was captured at `next_captured_at`.
+    pub fn duration_until(&mut self, captured_at: Instant, next_captured_at: Instant) -> Duration {
+        let origin = *self.origin.get_or_insert(captured_at);
+        // The clock after this sample should sit at the next sample's capture time.
+        let target = self.ticks(next_captured_at.saturating_duration_since(origin));
+        let delta = target.saturating_sub(self.emitted_ticks);
+        self.emitted_ticks += delta;
+        self.to_duration(delta)
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        ((duration.as_nanos() * self.clock_rate as u128 + 500_000_000) / 1_000_000_000) as u64
+    }
+
+    // Rounded up so the packetizer's truncating conversion back to ticks is lossless.
+    fn to_duration(&self, ticks: u64) -> Duration {
+        let nanos = (ticks as u128 * 1_000_000_000).div_ceil(self.clock_rate as u128);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packetizer_ticks(duration: Duration) -> u64 {
+        (duration.as_secs_f64() * 90000.0) as u64
+    }
+
+    #[test]
+    fn durations_follow_capture_times_without_drift() {
+        let mut clock = SampleClock::new(90000);
+        let start = Instant::now();
+        let frame = |i: u64| start + Duration::from_nanos(i * 33_333_333);
+        // The first sample is stamped 0 and the second one frame later.
+        let mut rtp = packetizer_ticks(clock.duration_until(start, frame(1)));
+        assert_eq!(rtp, 3000);
+
+        // A stalled capture stamps the late frame at its capture time instead of slowing
+        // playback down.
+        let stalled = start + Duration::from_millis(500);
+        rtp += packetizer_ticks(clock.duration_until(frame(1), stalled));
+        assert_eq!(rtp, 45000);
+
+        let mut previous = stalled;
+        for i in 1..=300u64 {
+            let at = stalled + Duration::from_nanos(i * 33_333_333);
+            rtp += packetizer_ticks(clock.duration_until(previous, at));
+            previous = at;
+        }
+        assert_eq!(rtp, 45000 + 900_000);
+    }
+
+    #[test]
+    fn frame_rate_is_shared_and_sanitized() {
+        let rate = FrameRate::new(30.0);
+        let handle = rate.clone();
+        handle.set(10.0);
+        assert_eq!(rate.interval(), Duration::from_millis(100));
+        handle.set(f64::NAN);
+        assert_eq!(rate.get(), DEFAULT_FPS);
+    }
+}
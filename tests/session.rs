@@ -0,0 +1,86 @@
+//! Runs on its own because it sets the process-wide peer connection and consent gate.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use webrtc::api::{media_engine::MediaEngine, APIBuilder};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc_client::{
+    consent::{self, ConsentDecision, ConsentGate, SessionEnd, ViewerRequest, WatchIndicator},
+    metrics::metrics,
+    model::{SdpImpl, SdpOfferAnswer},
+    sdp::{self, PRIMARY_SESSION},
+    RTC_CONFIG,
+};
+
+struct NoIndicator;
+
+impl WatchIndicator for NoIndicator {
+    fn watching(&self, _viewer: &ViewerRequest) {}
+
+    fn stopped(&self, _viewer: &ViewerRequest, _end: SessionEnd) {}
+}
+
+/// Send a fresh offer from `viewer` and apply the answer to it.
+async fn negotiate(viewer: &RTCPeerConnection) -> Result<()> {
+    let offer = viewer.create_offer(None).await?;
+    viewer.set_local_description(offer.clone()).await?;
+    let message = SdpOfferAnswer::new(Some(offer.to_json()), None, Some("viewer".into()));
+    let reply = sdp::answer_signed_offer(&message).await?;
+    let reply: SdpOfferAnswer = serde_json::from_str(reply.to_text()?)?;
+    let answer = serde_json::from_str(&reply.answer.unwrap())?;
+    viewer.set_remote_description(answer).await?;
+    Ok(())
+}
+
+async fn reported() -> bool {
+    let text = metrics().render().await;
+    text.contains(&format!("session=\"{PRIMARY_SESSION}\""))
+}
+
+#[tokio::test]
+async fn revoked_session_can_be_followed_by_a_new_one() {
+    sdp::init_sdp().await.unwrap();
+    let gate = ConsentGate::new(
+        |_: &ViewerRequest| ConsentDecision::Accept,
+        NoIndicator,
+        Duration::from_secs(1),
+    );
+    sdp::enable_consent(gate).unwrap();
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs().unwrap();
+    let viewer = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .build()
+        .new_peer_connection(Default::default())
+        .await
+        .unwrap();
+    viewer
+        .add_transceiver_from_kind(RTPCodecType::Video, None)
+        .await
+        .unwrap();
+
+    negotiate(&viewer).await.unwrap();
+    assert!(consent::sharing_allowed());
+    assert!(reported().await);
+
+    let revoked = sdp::revoke_session().await.unwrap();
+    assert_eq!(
+        revoked.map(|viewer| viewer.client_id),
+        Some("viewer".into())
+    );
+    assert!(!consent::sharing_allowed());
+    assert!(!reported().await);
+    let rtpc = RTC_CONFIG.get().unwrap();
+    assert_ne!(rtpc.connection_state(), RTCPeerConnectionState::Closed);
+
+    negotiate(&viewer).await.unwrap();
+    assert!(consent::sharing_allowed());
+    assert!(reported().await);
+
+    rtpc.close().await.unwrap();
+    assert!(!consent::sharing_allowed());
+    assert!(!reported().await);
+}